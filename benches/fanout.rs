@@ -0,0 +1,131 @@
+//! Cost of sending a change to thousands of subscribers of the same table,
+//! the message being serialized once per (format, encoding, select) and
+//! only tagged for the sessions with multiple subscriptions.
+//!
+//! The `per_subscriber` cases are the baseline: the message serialized
+//! (and tagged) for each subscriber, as it was before being shared.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde_json::json;
+use speculare_pgcdc::{
+    api::{
+        gate::Gate,
+        subscriptions::Subscriber,
+        ws_utils::{Encoding, MessageFormat, WsWatchFor, INSERT},
+    },
+    cdc::event::{ChangeEvent, ChangeKind, Column, Position},
+    forwarder::{
+        serializer,
+        websocket::{send_message, Batches},
+    },
+    utils::config::SlowConsumerPolicy,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+fn event() -> ChangeEvent {
+    let column = |name: &str, type_name: &str, value| Column {
+        name: name.to_owned(),
+        type_name: type_name.to_owned(),
+        value,
+    };
+    ChangeEvent {
+        schema: String::from("public"),
+        table: String::from("hosts"),
+        kind: ChangeKind::Insert,
+        columns: vec![
+            column(
+                "uuid",
+                "uuid",
+                json!("5b1b4e4a-1a2b-4c3d-8e9f-0a1b2c3d4e5f"),
+            ),
+            column("hostname", "text", json!("worker-042.eu-west.example.com")),
+            column("os", "text", json!("Ubuntu 22.04.3 LTS")),
+            column("cpus", "integer", json!(16)),
+            column("load_avg", "double precision", json!(0.42)),
+            column("memory", "bigint", json!(68_719_476_736u64)),
+            column(
+                "updated_at",
+                "timestamp without time zone",
+                json!("2024-01-01 10:00:00"),
+            ),
+        ],
+        old_keys: Vec::new(),
+        lsn: 0x16_B374_D848,
+        position: Position::new(0x16_B374_D900, 0),
+        commit_ts: None,
+        xid: Some(42),
+    }
+}
+
+/// count subscribers, each in its own session, using the encodings in turn
+fn subscribers(count: usize, encodings: &[Encoding], tagged: bool) -> Vec<Arc<Subscriber>> {
+    (0..count)
+        .map(|client| {
+            Arc::new(Subscriber {
+                client,
+                // Dropping the oldest messages keeps the cost of queuing constant
+                gate: Gate::with_limits(client, 16, SlowConsumerPolicy::DropOldest),
+                format: MessageFormat::Envelope,
+                encoding: encodings[client % encodings.len()],
+                tx: false,
+                tagged: Arc::new(AtomicBool::new(tagged)),
+                watch_for: Arc::new(WsWatchFor {
+                    id: format!("sub{}", client % 10),
+                    change_table: String::from("hosts"),
+                    change_flag: INSERT,
+                    specific: None,
+                    select: None,
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Send the change to the subscribers, serializing it for each of them
+fn per_subscriber(event: &ChangeEvent, subscribers: &[Arc<Subscriber>]) {
+    for subscriber in subscribers {
+        let sub = &subscriber.watch_for;
+        let value = serializer::serialize(event, subscriber.format, sub.select.as_deref());
+        let tag = match subscriber.tagged.load(Ordering::Relaxed) {
+            true => Some(sub.id.as_str()),
+            false => None,
+        };
+        let message = serializer::encode(&serializer::tagged(value, tag), subscriber.encoding);
+        subscriber.gate.send_change(message, event.position);
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let event = event();
+    let encodings = [Encoding::Json, Encoding::Msgpack, Encoding::Cbor];
+
+    let mut group = c.benchmark_group("fanout");
+    for count in [1_000, 10_000] {
+        for (name, encodings, tagged) in [
+            ("json", &encodings[..1], false),
+            ("json_tagged", &encodings[..1], true),
+            ("all_encodings_tagged", &encodings[..], true),
+        ] {
+            let subscribers = subscribers(count, encodings, tagged);
+            group.bench_with_input(BenchmarkId::new(name, count), &subscribers, |b, subs| {
+                let mut batches = Batches::new();
+                b.iter(|| send_message(&event, subs, &mut batches));
+            });
+            let baseline = format!("per_subscriber_{}", name);
+            group.bench_with_input(
+                BenchmarkId::new(baseline, count),
+                &subscribers,
+                |b, subs| {
+                    b.iter(|| per_subscriber(&event, subs));
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
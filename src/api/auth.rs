@@ -1,7 +1,9 @@
 use std::time::Duration;
 
+use super::ws_utils::WsWatchFor;
+
 use crate::{
-    utils::specific_filter::{DataType, SpecificFilter},
+    utils::specific_filter::{DataType, FilterExpr, Operator, SpecificFilter},
     CONFIG,
 };
 
@@ -12,6 +14,7 @@ use axum::{
 };
 use axum_extra::extract::SignedCookieJar;
 use diesel::{r2d2::ConnectionManager, PgConnection};
+use futures::future::{BoxFuture, FutureExt};
 use moka::sync::Cache;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
@@ -123,11 +126,66 @@ where
     }
 }
 
-pub async fn restrict_auth(auth: AuthInfo, specific: SpecificFilter) -> Result<(), ApiError> {
-    let auth_cookie = auth.auth_cookie.unwrap();
+/// Check that the user is authorized to listen to every subscriptions, each of
+/// them must have a filter restricting what it match.
+pub async fn restrict_subscriptions(
+    auth: &AuthInfo,
+    watch_for: &[WsWatchFor],
+) -> Result<(), ApiError> {
+    for sub in watch_for {
+        let specific = match &sub.specific {
+            Some(specific) => specific,
+            None => return Err(ApiError::InvalidRequestError(None)),
+        };
+
+        restrict_auth(auth, specific).await?;
+    }
+
+    Ok(())
+}
+
+/// Check that the user is authorized to listen to what the filter expression match.
+///
+/// At least one authorizing filter must hold in every branch of the expression:
+/// any child of an `and` is enough, but every child of an `or` must be authorized.
+pub fn restrict_auth<'a>(
+    auth: &'a AuthInfo,
+    expr: &'a FilterExpr,
+) -> BoxFuture<'a, Result<(), ApiError>> {
+    async move {
+        match expr {
+            FilterExpr::Filter(specific) => restrict_filter(auth, specific).await,
+            FilterExpr::And(exprs) => {
+                let mut res = Err(ApiError::AuthorizationError(None));
+                for expr in exprs {
+                    res = restrict_auth(auth, expr).await;
+                    if res.is_ok() {
+                        break;
+                    }
+                }
+                res
+            }
+            FilterExpr::Or(exprs) => {
+                for expr in exprs {
+                    restrict_auth(auth, expr).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+    .boxed()
+}
+
+async fn restrict_filter(auth: &AuthInfo, specific: &SpecificFilter) -> Result<(), ApiError> {
+    let auth_cookie = auth.auth_cookie.as_ref().unwrap();
+
+    // Only an equality can restrict what's being listened to
+    if specific.op != Operator::Eq {
+        return Err(ApiError::InvalidRequestError(None));
+    }
 
-    let sp_value = match as_variant!(specific.value, DataType::String) {
-        Some(val) => val,
+    let sp_value = match as_variant!(&specific.value, DataType::String) {
+        Some(val) => val.to_owned(),
         None => {
             return Err(ApiError::InvalidRequestError(None));
         }
@@ -144,7 +202,7 @@ pub async fn restrict_auth(auth: AuthInfo, specific: SpecificFilter) -> Result<(
 
         // Check if value is present in the cache, otherwise check the database
         let cuid = auth_cookie.user_id.clone();
-        if CHECKSESSIONS_CACHE.get(&sp_value) == Some(auth_cookie.user_id) {
+        if CHECKSESSIONS_CACHE.get(&sp_value).as_ref() == Some(&auth_cookie.user_id) {
             trace!("CheckSessions: cache hit for {}", &sp_value);
             return Ok(());
         }
@@ -0,0 +1,204 @@
+#[cfg(feature = "auth")]
+use super::auth::{self, AuthInfo};
+use super::{
+    gate::{Frame, Gate},
+    query,
+    ws_utils::{ServerState, WsWatchFor},
+};
+
+use serde::{Deserialize, Serialize};
+use sproot::apierrors::ApiError;
+use std::sync::{atomic::Ordering, Arc};
+
+/// Control messages a client can send over its websocket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum ControlRequest {
+    /// Add a subscription (same syntax as the `query` and `select` params),
+    /// the id default to the query itself.
+    Subscribe {
+        query: String,
+        id: Option<String>,
+        select: Option<String>,
+    },
+    /// Remove the subscription identified by id
+    Unsubscribe {
+        id: String,
+    },
+    /// List the ids of the current subscriptions
+    List,
+    /// Get the state of the queue of the client
+    Stats,
+    Ping,
+}
+
+/// Replies sent back to the client for each ControlRequest
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlReply {
+    Ack {
+        action: &'static str,
+        id: String,
+    },
+    Error {
+        action: Option<&'static str>,
+        message: String,
+    },
+    List {
+        subscriptions: Vec<String>,
+    },
+    Stats {
+        /// Messages dropped because the client was too slow
+        dropped: u64,
+        /// Messages waiting to be sent
+        queued: usize,
+    },
+    Pong,
+}
+
+/// Get a message we can send back to the client from the ApiError
+fn error_message(err: ApiError) -> String {
+    match err {
+        ApiError::ExplicitError(msg) => msg,
+        ApiError::AuthorizationError(_) => String::from("not authorized to subscribe to this"),
+        _ => String::from("invalid request"),
+    }
+}
+
+/// Handle a control message received from the client (session id) and reply to it
+pub async fn handle_control(
+    #[cfg(feature = "auth")] auth: &AuthInfo,
+    id: usize,
+    text: &str,
+    tx: &Gate,
+    state: &ServerState,
+) {
+    let reply = match serde_json::from_str::<ControlRequest>(text) {
+        Ok(request) => {
+            trace!("Websocket: control message from {}: {:?}", id, request);
+            match request {
+                ControlRequest::Subscribe {
+                    query,
+                    id: sub_id,
+                    select,
+                } => {
+                    match subscribe(
+                        #[cfg(feature = "auth")]
+                        auth,
+                        id,
+                        &query,
+                        sub_id,
+                        select.as_deref(),
+                        state,
+                    )
+                    .await
+                    {
+                        Ok(sub_id) => ControlReply::Ack {
+                            action: "subscribe",
+                            id: sub_id,
+                        },
+                        Err(err) => ControlReply::Error {
+                            action: Some("subscribe"),
+                            message: error_message(err),
+                        },
+                    }
+                }
+                ControlRequest::Unsubscribe { id: sub_id } => {
+                    if unsubscribe(id, &sub_id, state) {
+                        ControlReply::Ack {
+                            action: "unsubscribe",
+                            id: sub_id,
+                        }
+                    } else {
+                        ControlReply::Error {
+                            action: Some("unsubscribe"),
+                            message: String::from("no subscription with this id"),
+                        }
+                    }
+                }
+                ControlRequest::List => ControlReply::List {
+                    subscriptions: match state.clients.get(&id) {
+                        Some(client) => client.watch_for.iter().map(|w| w.id.clone()).collect(),
+                        None => Vec::new(),
+                    },
+                },
+                ControlRequest::Stats => ControlReply::Stats {
+                    dropped: tx.dropped(),
+                    queued: tx.queued(),
+                },
+                ControlRequest::Ping => ControlReply::Pong,
+            }
+        }
+        Err(err) => ControlReply::Error {
+            action: None,
+            message: format!("invalid control message: {}", err),
+        },
+    };
+
+    // serde_json::to_string cannot fail on our own enum
+    let reply = serde_json::to_string(&reply).unwrap();
+    if !tx.send_control(Frame::text(reply)) {
+        error!("Websocket: cannot reply to the control message, client disconnected");
+    }
+}
+
+/// Add the subscription to the session (and register it in the ServerState)
+async fn subscribe(
+    #[cfg(feature = "auth")] auth: &AuthInfo,
+    id: usize,
+    query: &str,
+    sub_id: Option<String>,
+    select: Option<&str>,
+    state: &ServerState,
+) -> Result<String, ApiError> {
+    let mut watch_for: WsWatchFor = query::parse_ws_query(query)?;
+    if let Some(sub_id) = sub_id {
+        watch_for.id = sub_id;
+    }
+    if let Some(select) = select {
+        query::apply_select(&mut watch_for, select)?;
+    }
+
+    // Same restriction as when connecting
+    #[cfg(feature = "auth")]
+    {
+        if !auth.is_admin {
+            auth::restrict_subscriptions(auth, std::slice::from_ref(&watch_for)).await?;
+        }
+    }
+
+    let sub_id = watch_for.id.clone();
+    let mut client = match state.clients.get_mut(&id) {
+        Some(client) => client,
+        None => return Err(ApiError::ServerError(None)),
+    };
+    if client.watch_for.iter().any(|w| w.id == sub_id) {
+        return Err(ApiError::ExplicitError(String::from(
+            "a subscription with this id already exists",
+        )));
+    }
+
+    // The messages of every subscription of the session are now tagged
+    client.tagged.store(true, Ordering::Relaxed);
+
+    let watch_for = Arc::new(watch_for);
+    state.register(id, &client, Arc::clone(&watch_for));
+    client.watch_for.push(watch_for);
+
+    Ok(sub_id)
+}
+
+/// Remove the subscription from the session, return false if it did not exist
+fn unsubscribe(id: usize, sub_id: &str, state: &ServerState) -> bool {
+    let removed = match state.clients.get_mut(&id) {
+        Some(mut client) => match client.watch_for.iter().position(|w| w.id == sub_id) {
+            Some(idx) => client.watch_for.remove(idx),
+            None => return false,
+        },
+        None => return false,
+    };
+
+    state.unregister(id, &removed);
+
+    true
+}
@@ -0,0 +1,307 @@
+use crate::{cdc::event::Position, utils::config::SlowConsumerPolicy, CONFIG};
+
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+/// Close code sent to the clients disconnected for being too slow (Policy Violation)
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 1008;
+/// Close code sent to the clients disconnected for a transaction too big (Message Too Big)
+pub const TOO_BIG_CLOSE_CODE: u16 = 1009;
+
+/// A frame to send to a client, cheap to clone: the messages are shared
+/// between every clients receiving them and written without being copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// UTF-8 text, see Frame::text
+    Text(Bytes),
+    Binary(Bytes),
+    /// Close the connection with the code and reason
+    Close(u16, &'static str),
+}
+
+impl Frame {
+    pub fn text(text: String) -> Self {
+        Frame::Text(Bytes::from(text))
+    }
+}
+
+/// Bounded queue of the messages waiting to be sent to a client.
+///
+/// When the queue is full, the slow_consumer_policy of the config decide what happens.
+/// The replies to the client (see Gate::send_control) are only dropped by the
+/// drop_oldest and drop_newest policies if nothing but replies is queued.
+#[derive(Clone)]
+pub struct Gate {
+    inner: Arc<GateInner>,
+}
+
+struct GateInner {
+    /// Session id of the client, for the logs
+    id: usize,
+    /// Max number of messages queued, and what to do once reached
+    size: usize,
+    policy: SlowConsumerPolicy,
+    queue: Mutex<VecDeque<Entry>>,
+    notify: Notify,
+    closed: AtomicBool,
+    /// Is the client currently too slow (to only log it once)
+    slow: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// A queued message, along with the position of the change it carries (if any)
+pub type Queued = (Frame, Option<Position>);
+
+struct Entry {
+    message: Queued,
+    /// Is it a reply to the client (control message, pong, close)
+    control: bool,
+}
+
+impl Gate {
+    pub fn new(id: usize) -> Self {
+        Self::with_limits(id, CONFIG.client_queue_size, CONFIG.slow_consumer_policy)
+    }
+
+    /// Gate holding up to size messages, the policy deciding what happens once full
+    pub fn with_limits(id: usize, size: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            inner: Arc::new(GateInner {
+                id,
+                size,
+                policy,
+                queue: Mutex::new(VecDeque::with_capacity(size)),
+                notify: Notify::new(),
+                closed: AtomicBool::new(false),
+                slow: AtomicBool::new(false),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Queue the message, return false if the gate is closed (client disconnected)
+    pub fn send(&self, message: Frame) -> bool {
+        self.push((message, None), false)
+    }
+
+    /// Queue the message of a change (at the position), same as send otherwise
+    pub fn send_change(&self, message: Frame, position: Position) -> bool {
+        self.push((message, Some(position)), false)
+    }
+
+    /// Queue a reply to the client (control reply, pong or close), a change
+    /// being dropped in its place if the queue is full.
+    pub fn send_control(&self, message: Frame) -> bool {
+        self.push((message, None), true)
+    }
+
+    fn push(&self, message: Queued, control: bool) -> bool {
+        let inner = &self.inner;
+        if inner.closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        {
+            let mut queue = inner.queue.lock().unwrap();
+            if queue.len() >= inner.size {
+                if !inner.slow.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Websocket: client {} is too slow, its queue is full ({:?})",
+                        inner.id, inner.policy
+                    );
+                }
+
+                // Index of the message dropped to make room, None to drop the new one
+                let dropped = match inner.policy {
+                    // The oldest change, or the oldest reply if there's only replies
+                    SlowConsumerPolicy::DropOldest => queue
+                        .iter()
+                        .position(|entry| !entry.control)
+                        .or(control.then_some(0)),
+                    SlowConsumerPolicy::DropNewest if control => {
+                        queue.iter().rposition(|entry| !entry.control)
+                    }
+                    SlowConsumerPolicy::DropNewest => None,
+                    SlowConsumerPolicy::Disconnect => {
+                        // Only the close frame is left to send
+                        inner
+                            .dropped
+                            .fetch_add(queue.len() as u64 + 1, Ordering::Relaxed);
+                        queue.clear();
+                        queue.push_back(Entry {
+                            message: (
+                                Frame::Close(SLOW_CONSUMER_CLOSE_CODE, "slow consumer"),
+                                None,
+                            ),
+                            control: true,
+                        });
+                        inner.closed.store(true, Ordering::Release);
+                        drop(queue);
+                        inner.notify.notify_one();
+                        return false;
+                    }
+                };
+                if let Some(idx) = dropped {
+                    queue.remove(idx);
+                    queue.push_back(Entry { message, control });
+                }
+                inner.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                queue.push_back(Entry { message, control });
+            }
+        }
+
+        inner.notify.notify_one();
+        true
+    }
+
+    /// Reject a message too big to be sent (a transaction of more than tx_max_changes),
+    /// it is dropped or, with the disconnect policy, the client is disconnected once
+    /// the messages already queued are sent.
+    pub fn reject(&self) {
+        let inner = &self.inner;
+        if inner.closed.load(Ordering::Acquire) {
+            return;
+        }
+
+        warn!(
+            "Websocket: a transaction too big for client {} was not sent ({:?})",
+            inner.id, inner.policy
+        );
+        inner.dropped.fetch_add(1, Ordering::Relaxed);
+        if let SlowConsumerPolicy::Disconnect = inner.policy {
+            inner.queue.lock().unwrap().push_back(Entry {
+                message: (
+                    Frame::Close(TOO_BIG_CLOSE_CODE, "transaction too big"),
+                    None,
+                ),
+                control: true,
+            });
+            self.close();
+        }
+    }
+
+    /// Wait for the next message to send, None once the gate is closed and empty
+    pub async fn recv(&self) -> Option<Queued> {
+        let inner = &self.inner;
+        loop {
+            {
+                let mut queue = inner.queue.lock().unwrap();
+                if let Some(entry) = queue.pop_front() {
+                    return Some(entry.message);
+                }
+                // The client caught up
+                inner.slow.store(false, Ordering::Relaxed);
+            }
+            if inner.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            inner.notify.notified().await;
+        }
+    }
+
+    /// Close the gate, the messages already queued are still sent
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.notify.notify_one();
+    }
+
+    /// Number of messages dropped because the client was too slow
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn queued(&self) -> usize {
+        self.inner.queue.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(n: u64) -> Frame {
+        Frame::text(format!("change {}", n))
+    }
+
+    fn reply(n: u64) -> Frame {
+        Frame::text(format!("reply {}", n))
+    }
+
+    /// Fill the gate with two changes and a reply, then send more of each
+    async fn overflow(policy: SlowConsumerPolicy) -> (Gate, Vec<Frame>) {
+        let gate = Gate::with_limits(0, 3, policy);
+        assert!(gate.send_change(change(1), Position::new(1, 0)));
+        assert!(gate.send_control(reply(1)));
+        assert!(gate.send_change(change(2), Position::new(2, 0)));
+        gate.send_change(change(3), Position::new(3, 0));
+        gate.send_control(reply(2));
+
+        gate.close();
+        let mut frames = Vec::new();
+        while let Some((frame, _)) = gate.recv().await {
+            frames.push(frame);
+        }
+        (gate, frames)
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_replies() {
+        let (gate, frames) = overflow(SlowConsumerPolicy::DropOldest).await;
+        assert_eq!(frames, vec![reply(1), change(3), reply(2)]);
+        assert_eq!(gate.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_replies() {
+        let (gate, frames) = overflow(SlowConsumerPolicy::DropNewest).await;
+        assert_eq!(frames, vec![change(1), reply(1), reply(2)]);
+        assert_eq!(gate.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn only_replies_queued() {
+        let gate = Gate::with_limits(0, 2, SlowConsumerPolicy::DropNewest);
+        gate.send_control(reply(1));
+        gate.send_control(reply(2));
+        gate.send_control(reply(3));
+        gate.send_change(change(1), Position::new(1, 0));
+        assert_eq!(gate.queued(), 2);
+        assert_eq!(gate.dropped(), 2);
+
+        let gate = Gate::with_limits(0, 2, SlowConsumerPolicy::DropOldest);
+        gate.send_control(reply(1));
+        gate.send_control(reply(2));
+        gate.send_control(reply(3));
+        gate.send_change(change(1), Position::new(1, 0));
+        gate.close();
+        assert_eq!(gate.recv().await.map(|(frame, _)| frame), Some(reply(2)));
+        assert_eq!(gate.recv().await.map(|(frame, _)| frame), Some(reply(3)));
+        assert_eq!(gate.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_gate() {
+        let gate = Gate::with_limits(0, 1, SlowConsumerPolicy::Disconnect);
+        assert!(gate.send_change(change(1), Position::new(1, 0)));
+        assert!(!gate.send_change(change(2), Position::new(2, 0)));
+        assert!(!gate.send_control(reply(1)));
+        assert_eq!(
+            gate.recv().await,
+            Some((
+                Frame::Close(SLOW_CONSUMER_CLOSE_CODE, "slow consumer"),
+                None
+            ))
+        );
+        assert_eq!(gate.recv().await, None);
+        assert_eq!(gate.dropped(), 2);
+    }
+}
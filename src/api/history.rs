@@ -0,0 +1,168 @@
+use crate::{
+    cdc::event::{ChangeEvent, Position},
+    CONFIG,
+};
+
+use std::{collections::VecDeque, sync::Arc};
+
+/// The last changes forwarded (sse_history_size of them), replayed to the
+/// SSE clients resuming from a Last-Event-ID.
+#[derive(Default)]
+pub struct History {
+    events: VecDeque<Arc<ChangeEvent>>,
+    /// Every change after this position is (still) in the history, None until
+    /// the first change is received.
+    complete_after: Option<Position>,
+    /// Are the last changes part of a transaction not committed yet
+    open: bool,
+}
+
+impl History {
+    /// Add the change to the history, evicting the oldest one if full
+    pub fn push(&mut self, event: Arc<ChangeEvent>) {
+        self.push_bounded(event, CONFIG.sse_history_size)
+    }
+
+    fn push_bounded(&mut self, event: Arc<ChangeEvent>, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        self.open = true;
+        // A transaction interrupted by the loss of the stream is sent again (as a
+        // whole) once resumed, the changes already known are not added twice.
+        if matches!(self.events.back(), Some(last) if last.position >= event.position) {
+            return;
+        }
+
+        if self.complete_after.is_none() {
+            // What came before the first change (if anything) is unknown
+            let position = event.position;
+            self.complete_after = Some(match position.index.checked_sub(1) {
+                Some(index) => Position::new(position.commit_lsn, index),
+                None => Position::new(position.commit_lsn.saturating_sub(1), u32::MAX),
+            });
+        }
+        if self.events.len() >= size {
+            if let Some(evicted) = self.events.pop_front() {
+                self.complete_after = Some(evicted.position);
+            }
+        }
+        self.events.push_back(event);
+    }
+
+    /// The transaction of the last changes is over (committed or interrupted)
+    pub fn end_transaction(&mut self) {
+        self.open = false;
+    }
+
+    /// Are the last changes part of a transaction not committed yet
+    pub fn open(&self) -> bool {
+        self.open
+    }
+
+    /// Get the changes made after the position, None if some of them are not
+    /// in the history anymore (or never were).
+    pub fn since(&self, position: Position) -> Option<Vec<Arc<ChangeEvent>>> {
+        match self.complete_after {
+            Some(complete_after) if position >= complete_after => Some(
+                self.events
+                    .iter()
+                    .filter(|event| event.position > position)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cdc::event::ChangeKind;
+
+    /// A change at the position, sharing the LSN of its transaction (as with wal2json v1)
+    fn change(commit_lsn: u64, index: u32) -> Arc<ChangeEvent> {
+        Arc::new(ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Insert,
+            columns: Vec::new(),
+            old_keys: Vec::new(),
+            lsn: commit_lsn - 10,
+            position: Position::new(commit_lsn, index),
+            commit_ts: None,
+            xid: None,
+        })
+    }
+
+    fn filled(size: usize, changes: &[(u64, u32)]) -> History {
+        let mut history = History::default();
+        for &(commit_lsn, index) in changes {
+            history.push_bounded(change(commit_lsn, index), size);
+        }
+        history
+    }
+
+    fn since(history: &History, commit_lsn: u64, index: u32) -> Option<Vec<(u64, u32)>> {
+        history
+            .since(Position::new(commit_lsn, index))
+            .map(|events| {
+                events
+                    .iter()
+                    .map(|event| (event.position.commit_lsn, event.position.index))
+                    .collect()
+            })
+    }
+
+    #[test]
+    fn resume_inside_a_transaction() {
+        let history = filled(10, &[(100, 0), (100, 1), (100, 2), (200, 0)]);
+        assert_eq!(
+            since(&history, 100, 0),
+            Some(vec![(100, 1), (100, 2), (200, 0)])
+        );
+        assert_eq!(since(&history, 100, 2), Some(vec![(200, 0)]));
+        assert_eq!(since(&history, 200, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn eviction_inside_a_transaction() {
+        // (100, 0) and (100, 1) are evicted
+        let history = filled(3, &[(100, 0), (100, 1), (100, 2), (200, 0), (200, 1)]);
+        assert_eq!(since(&history, 100, 0), None);
+        assert_eq!(
+            since(&history, 100, 1),
+            Some(vec![(100, 2), (200, 0), (200, 1)])
+        );
+    }
+
+    #[test]
+    fn unknown_before_the_first_change() {
+        let history = filled(10, &[(100, 0), (100, 1)]);
+        assert_eq!(since(&history, 50, 3), None);
+        assert_eq!(since(&history, 100, 0), Some(vec![(100, 1)]));
+
+        // Only the change right before is known to be complete
+        let history = filled(10, &[(100, 2), (100, 3)]);
+        assert_eq!(since(&history, 100, 0), None);
+        assert_eq!(since(&history, 100, 1), Some(vec![(100, 2), (100, 3)]));
+    }
+
+    #[test]
+    fn transaction_sent_again() {
+        // The stream was lost in the middle of the second transaction
+        let mut history = filled(10, &[(100, 0), (200, 0), (200, 1)]);
+        history.end_transaction();
+        for index in 0..3 {
+            history.push_bounded(change(200, index), 10);
+        }
+        assert!(history.open());
+        assert_eq!(
+            since(&history, 100, 0),
+            Some(vec![(200, 0), (200, 1), (200, 2)])
+        );
+    }
+}
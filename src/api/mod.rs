@@ -5,8 +5,13 @@ use axum_extra::extract::cookie::Key;
 
 #[cfg(feature = "auth")]
 pub mod auth;
+pub mod control;
+pub mod gate;
+pub mod history;
 pub mod query;
 pub mod server;
+pub mod sse_handler;
+pub mod subscriptions;
 pub mod ws_handler;
 pub mod ws_utils;
 
@@ -1,14 +1,117 @@
-use super::ws_utils::{self, WsWatchFor};
+use super::ws_utils::{self, Encoding, MessageFormat, WsWatchFor, DELETE};
 
 use crate::{
-    utils::specific_filter::{DataType, SpecificFilter},
-    TABLES,
+    utils::specific_filter::{DataType, FilterExpr, Operator, SpecificFilter},
+    COLUMNS, TABLES,
 };
 
 use sproot::apierrors::ApiError;
 
+/// Subscriptions and options of a session, as asked for in the query params
+pub struct SessionParams {
+    pub watch_for: Vec<WsWatchFor>,
+    pub format: MessageFormat,
+    /// None if not asked for (the websocket's sub-protocol can then be used)
+    pub encoding: Option<Encoding>,
+    /// Does the client want the system messages
+    pub system: bool,
+    /// Does the client want the changes of a transaction in a single message
+    pub tx: bool,
+}
+
+/// Parse the query params of a session: the subscriptions (`query`, `select`)
+/// and the options (`format`, `encoding`, `system`, `tx`).
+pub fn parse_session_params(params: &[(String, String)]) -> Result<SessionParams, ApiError> {
+    // Extract the query params (can be repeated) and construct the watch_for
+    // of each subscription from them
+    let mut watch_for = parse_ws_queries(
+        params
+            .iter()
+            .filter(|(key, _)| key == "query")
+            .map(|(_, value)| value.as_str()),
+    )?;
+
+    // Only send the columns asked for (if any), failing if one does not exists
+    if let Some((_, select)) = params.iter().find(|(key, _)| key == "select") {
+        apply_session_select(&mut watch_for, select)?;
+    }
+
+    // The shape of the messages, default to the legacy wal2json's one
+    let format = match params.iter().find(|(key, _)| key == "format") {
+        Some((_, format)) => match MessageFormat::from_name(format) {
+            Some(format) => format,
+            None => {
+                return Err(ApiError::ExplicitError(format!(
+                    "the format `{}` is not supported",
+                    format
+                )))
+            }
+        },
+        None => MessageFormat::default(),
+    };
+
+    // The encoding can be asked for using a param, or using the websocket sub-protocol
+    let encoding = match params.iter().find(|(key, _)| key == "encoding") {
+        Some((_, encoding)) => match Encoding::from_name(encoding) {
+            Some(encoding) => Some(encoding),
+            None => {
+                return Err(ApiError::ExplicitError(format!(
+                    "the encoding `{}` is not supported",
+                    encoding
+                )))
+            }
+        },
+        None => None,
+    };
+
+    // The system messages (stream_down, ...) are opt-in, not to confuse legacy clients
+    let system = params
+        .iter()
+        .any(|(key, value)| key == "system" && (value == "true" || value == "1"));
+
+    // Batch the changes by transaction
+    let tx = params
+        .iter()
+        .any(|(key, value)| key == "tx" && (value == "true" || value == "1"));
+
+    Ok(SessionParams {
+        watch_for,
+        format,
+        encoding,
+        system,
+        tx,
+    })
+}
+
+/// Parse all the subscriptions of a Ws, each `query` can hold multiple
+/// subscriptions separated by a `;`.
+pub fn parse_ws_queries<'a, I>(queries: I) -> Result<Vec<WsWatchFor>, ApiError>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut watch_for = Vec::new();
+    for query in queries {
+        for subscription in query.split(';').filter(|q| !q.is_empty()) {
+            let sub = parse_ws_query(subscription)?;
+            // The subscription id must be unique as it's used to tag the messages
+            if !watch_for.iter().any(|w: &WsWatchFor| w.id == sub.id) {
+                watch_for.push(sub);
+            }
+        }
+    }
+
+    if watch_for.is_empty() {
+        return Err(ApiError::ExplicitError(String::from(
+            "missing the query params",
+        )));
+    }
+
+    Ok(watch_for)
+}
+
 pub fn parse_ws_query(query: &str) -> Result<WsWatchFor, ApiError> {
-    let mut parts = query.split(':');
+    // The filter's value can contains `:` (timestamps for example)
+    let mut parts = query.splitn(3, ':');
     let mut change_flag = 0;
 
     // Apply bit operation to the change_flag based on the query type
@@ -48,38 +151,323 @@ pub fn parse_ws_query(query: &str) -> Result<WsWatchFor, ApiError> {
         }
     };
 
-    // Construct the SpecificFilter from the request
-    let specific: Option<SpecificFilter> = if let Some(filter) = parts.next() {
-        let mut fparts = filter.splitn(3, '.');
-        // let mut fparts = filter.splitn(2, ".eq.");
-        match (fparts.next(), fparts.next(), fparts.next()) {
-            (Some(col), Some(eq), Some(val)) => match eq {
-                "eq" => Some(SpecificFilter {
-                    column: serde_json::Value::String(col.to_owned()),
-                    value: DataType::String(val.to_owned()),
-                }),
-                "in" => {
-                    let items = val
-                        .split(',')
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>();
-                    Some(SpecificFilter {
-                        column: serde_json::Value::String(col.to_owned()),
-                        value: DataType::Array(items),
-                    })
-                }
-                _ => None,
-            },
-            _ => None,
-        }
-    } else {
-        None
+    // Construct the FilterExpr from the request, an empty one meaning no filter
+    let specific = match parts.next() {
+        Some(filter) if !filter.is_empty() => Some(parse_filter_expr(filter)?),
+        _ => None,
     };
 
+    // Deletes only carry the old image of the row, filtering them
+    // on other columns will never match.
+    if let Some(specific) = &specific {
+        if has_bit!(change_flag, DELETE) {
+            check_replica_identity(&change_table, specific);
+        }
+    }
+
     // Construct what the client is listening to
     Ok(WsWatchFor {
+        id: query.to_owned(),
         change_table,
         change_flag,
         specific,
+        select: None,
+    })
+}
+
+/// Warn if the filter use columns which are not part of the REPLICA IDENTITY of the table
+fn check_replica_identity(change_table: &str, specific: &FilterExpr) {
+    if let Some(table) = COLUMNS.read().unwrap().get(change_table) {
+        for column in specific.columns() {
+            if !table.identity.iter().any(|c| c == column) {
+                warn!(
+                    "The column {}.{} is not part of the REPLICA IDENTITY ({:?}) of the table, its deletes will never match the filter",
+                    change_table, column, table.replica_identity
+                );
+            }
+        }
+    }
+}
+
+/// Apply the select param of a session to each of its subscriptions, using only the
+/// columns of its table. The subscriptions whose table has none of them are not
+/// restricted, a column which is in none of the tables is an error.
+fn apply_session_select(watch_for: &mut [WsWatchFor], select: &str) -> Result<(), ApiError> {
+    let selected: Vec<&str> = select.split(',').filter(|c| !c.is_empty()).collect();
+
+    // The columns of select each subscription's table has
+    let scoped: Vec<Vec<&str>> = {
+        let columns = COLUMNS.read().unwrap();
+        let in_table = |sub: &WsWatchFor, column: &str| {
+            columns
+                .get(&sub.change_table)
+                .is_some_and(|table| table.columns.iter().any(|c| c == column))
+        };
+
+        if let Some(column) = selected
+            .iter()
+            .find(|column| !watch_for.iter().any(|sub| in_table(sub, column)))
+        {
+            return Err(ApiError::ExplicitError(format!(
+                "the column `{}` does not exists in the tables asked for",
+                column
+            )));
+        }
+
+        watch_for
+            .iter()
+            .map(|sub| {
+                selected
+                    .iter()
+                    .copied()
+                    .filter(|column| in_table(sub, column))
+                    .collect()
+            })
+            .collect()
+    };
+
+    for (sub, columns) in watch_for.iter_mut().zip(scoped) {
+        if !columns.is_empty() {
+            apply_select(sub, &columns.join(","))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restrict the columns sent for the subscription to those of select (`col1,col2`),
+/// the key columns of the table are always kept.
+pub fn apply_select(watch_for: &mut WsWatchFor, select: &str) -> Result<(), ApiError> {
+    let columns = COLUMNS.read().unwrap();
+    let table = match columns.get(&watch_for.change_table) {
+        Some(table) => table,
+        None => {
+            return Err(ApiError::ExplicitError(String::from(
+                "the columns of the table asked for are not known",
+            )))
+        }
+    };
+
+    let mut selected = table.keys.clone();
+    for column in select.split(',').filter(|c| !c.is_empty()) {
+        // Check if the column exists in the table
+        if !table.columns.iter().any(|c| c == column) {
+            return Err(ApiError::ExplicitError(format!(
+                "the column `{}` does not exists in the table `{}`",
+                column, watch_for.change_table
+            )));
+        }
+        if !selected.iter().any(|c| c == column) {
+            selected.push(column.to_owned());
+        }
+    }
+
+    watch_for.select = Some(selected);
+    Ok(())
+}
+
+/// Parse a filter expression, either a single filter or a combination of them
+/// using `and(...)` and `or(...)`, ex: `or(host_uuid.in.(a,b),and(customer_id.eq.X,usage.gt.90))`
+pub fn parse_filter_expr(filter: &str) -> Result<FilterExpr, ApiError> {
+    let group = |prefix: &str| {
+        filter
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix('('))
+            .and_then(|rest| rest.strip_suffix(')'))
+    };
+
+    if let Some(inner) = group("and") {
+        return Ok(FilterExpr::And(parse_filter_list(inner)?));
+    }
+    if let Some(inner) = group("or") {
+        return Ok(FilterExpr::Or(parse_filter_list(inner)?));
+    }
+
+    Ok(FilterExpr::Filter(parse_filter(filter)?))
+}
+
+/// Parse the comma separated expressions of an `and(...)` or `or(...)`
+fn parse_filter_list(list: &str) -> Result<Vec<FilterExpr>, ApiError> {
+    let mut exprs = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    // Only split on the commas which are not inside parenthesis
+    for (idx, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth.checked_sub(1).ok_or_else(|| {
+                    ApiError::ExplicitError(String::from("unbalanced parenthesis in the filter"))
+                })?
+            }
+            ',' if depth == 0 => {
+                exprs.push(parse_filter_expr(&list[start..idx])?);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(ApiError::ExplicitError(String::from(
+            "unbalanced parenthesis in the filter",
+        )));
+    }
+    exprs.push(parse_filter_expr(&list[start..])?);
+
+    Ok(exprs)
+}
+
+/// Parse a filter of the form `col.op.val` (`col.not.in.a,b,c` for not.in)
+pub fn parse_filter(filter: &str) -> Result<SpecificFilter, ApiError> {
+    let mut fparts = filter.splitn(3, '.');
+    let (col, op, val) = match (fparts.next(), fparts.next(), fparts.next()) {
+        (Some(col), Some("not"), Some(rest)) => match rest.split_once('.') {
+            Some((op, val)) => (col, format!("not.{}", op), val),
+            None => (col, format!("not.{}", rest), ""),
+        },
+        (Some(col), Some(op), Some(val)) => (col, op.to_owned(), val),
+        _ => {
+            return Err(ApiError::ExplicitError(String::from(
+                "the filter must be of the form column.operator.value",
+            )))
+        }
+    };
+
+    if col.is_empty() || !col.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ApiError::ExplicitError(format!(
+            "the filter column `{}` is not valid",
+            col
+        )));
+    }
+
+    let op = match Operator::from_name(&op) {
+        Some(op) => op,
+        None => {
+            return Err(ApiError::ExplicitError(format!(
+                "the filter operator `{}` is not supported",
+                op
+            )))
+        }
+    };
+
+    let value = match op {
+        // The list can be wrapped in parenthesis (mandatory inside and/or)
+        Operator::In | Operator::NotIn => DataType::Array(
+            val.strip_prefix('(')
+                .and_then(|v| v.strip_suffix(')'))
+                .unwrap_or(val)
+                .split(',')
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>(),
+        ),
+        Operator::Is => match val {
+            "null" => DataType::Null,
+            _ => {
+                return Err(ApiError::ExplicitError(String::from(
+                    "the `is` operator only support `null`",
+                )))
+            }
+        },
+        _ => DataType::String(val.to_owned()),
+    };
+
+    Ok(SpecificFilter {
+        column: col.to_owned(),
+        op,
+        value,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cdc::{ReplicaIdentity, TableColumns};
+
+    fn with_table(table: &str) {
+        let mut tables = TABLES.write().unwrap();
+        if !tables.iter().any(|t| t == table) {
+            tables.push(table.to_owned());
+        }
+    }
+
+    #[test]
+    fn empty_filter_is_no_filter() {
+        with_table("hosts");
+
+        let sub = parse_ws_query("insert:hosts:").unwrap();
+        assert!(sub.specific.is_none());
+        assert_eq!(sub.id, "insert:hosts:");
+
+        let sub = parse_ws_query("insert:hosts:uuid.eq.X").unwrap();
+        assert!(sub.specific.is_some());
+    }
+
+    fn with_columns(table: &str, columns: &[&str]) {
+        with_table(table);
+        COLUMNS.write().unwrap().insert(
+            table.to_owned(),
+            TableColumns {
+                columns: columns.iter().map(|c| c.to_string()).collect(),
+                types: columns.iter().map(|_| String::from("text")).collect(),
+                keys: vec![columns[0].to_owned()],
+                replica_identity: ReplicaIdentity::Default,
+                identity: vec![columns[0].to_owned()],
+            },
+        );
+    }
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn select_is_scoped_to_the_tables() {
+        with_columns("select_hosts", &["uuid", "os", "hostname"]);
+        with_columns("select_cpus", &["host_uuid", "usage", "created_at"]);
+
+        let session = parse_session_params(&params(&[
+            ("query", "insert:select_hosts;insert:select_cpus"),
+            ("select", "os,usage"),
+        ]))
+        .unwrap();
+        let selects: Vec<Option<Vec<String>>> = session
+            .watch_for
+            .iter()
+            .map(|sub| sub.select.clone())
+            .collect();
+        assert_eq!(
+            selects,
+            vec![
+                Some(vec![String::from("uuid"), String::from("os")]),
+                Some(vec![String::from("host_uuid"), String::from("usage")]),
+            ]
+        );
+
+        // The table without any of the columns is not restricted
+        let session = parse_session_params(&params(&[
+            ("query", "insert:select_hosts;insert:select_cpus"),
+            ("select", "usage"),
+        ]))
+        .unwrap();
+        assert!(session.watch_for[0].select.is_none());
+        assert!(session.watch_for[1].select.is_some());
+
+        // A column in none of the tables is still an error
+        assert!(parse_session_params(&params(&[
+            ("query", "insert:select_hosts;insert:select_cpus"),
+            ("select", "usage,missing"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn unknown_table() {
+        assert!(parse_ws_query("insert:not_a_table").is_err());
+        assert!(parse_ws_query("not_a_change:hosts").is_err());
+    }
+}
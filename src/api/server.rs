@@ -1,6 +1,6 @@
 #[cfg(feature = "auth")]
 use super::AppState;
-use super::{ws_handler, ws_utils::ServerState};
+use super::{sse_handler, ws_handler, ws_utils::ServerState};
 
 use crate::CONFIG;
 
@@ -25,6 +25,7 @@ pub async fn serve(serv_state: Arc<ServerState>) {
     let app = Router::new()
         .route("/ping", any(|| async { "zpour" }))
         .route("/ws", get(ws_handler::accept_conn))
+        .route("/sse", get(sse_handler::accept_sse))
         // logging so we can see whats going on
         .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default()))
         .layer(Extension(serv_state));
@@ -0,0 +1,170 @@
+#[cfg(feature = "auth")]
+use super::auth::{self, AuthInfo};
+
+use crate::{cdc::event::Position, forwarder::websocket, ID_COUNTER};
+
+use axum::{
+    extract::Query,
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use futures::{stream, Stream};
+use sproot::apierrors::ApiError;
+use std::{
+    convert::Infallible,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use super::{
+    gate::{Frame, Gate},
+    query::{self, SessionParams},
+    subscriptions::Subscriber,
+    ws_utils::{Encoding, ServerState, SessionInfo, SystemMessage},
+};
+
+/// Server-Sent Events alternative to the websocket, for the clients which
+/// only need to listen. Each change is sent as a `data:` event whose id is
+/// its position, allowing the client to resume using the Last-Event-ID header.
+pub async fn accept_sse(
+    #[cfg(feature = "auth")] auth: AuthInfo,
+    Extension(state): Extension<Arc<ServerState>>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    // Same params as the websocket, if error, bad request
+    let SessionParams {
+        watch_for,
+        format,
+        encoding,
+        system,
+        tx,
+    } = query::parse_session_params(&params)?;
+
+    // The events can only carry text
+    if matches!(encoding, Some(encoding) if encoding != Encoding::Json) {
+        return Err(ApiError::ExplicitError(String::from(
+            "only the json encoding is supported with SSE",
+        )));
+    }
+
+    #[cfg(feature = "auth")]
+    {
+        if !auth.is_admin {
+            auth::restrict_subscriptions(&auth, &watch_for).await?;
+        }
+    }
+
+    // The id of the last event received (a position, ex: 16/B374D848:3), if resuming
+    let last_event_id = match headers.get("last-event-id") {
+        Some(value) => {
+            let value = value.to_str().unwrap_or_default();
+            match Position::parse(value) {
+                Some(position) => Some((value.to_owned(), position)),
+                None => {
+                    return Err(ApiError::ExplicitError(format!(
+                        "the Last-Event-ID `{}` is not a valid position",
+                        value
+                    )))
+                }
+            }
+        }
+        None => None,
+    };
+
+    let id = ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    trace!("SSE: client connected: {}", id);
+
+    let gate = Gate::new(id);
+    sse_connected(
+        id,
+        SessionInfo {
+            gate: gate.clone(),
+            tagged: Arc::new(AtomicBool::new(watch_for.len() > 1)),
+            watch_for: watch_for.into_iter().map(Arc::new).collect(),
+            format,
+            encoding: Encoding::Json,
+            system,
+            tx,
+        },
+        last_event_id,
+        &state,
+    );
+
+    // The guard is dropped along with the stream, once the client disconnected
+    let guard = SseSession { id, state };
+    let stream = stream::unfold((gate, guard), |(gate, guard)| async move {
+        loop {
+            let (message, lsn) = gate.recv().await?;
+            let event = match (&message, lsn) {
+                // Always valid, built from Strings
+                (Frame::Text(text), Some(position)) => Event::default()
+                    .id(position.to_string())
+                    .data(String::from_utf8_lossy(text)),
+                (Frame::Text(text), None) => Event::default()
+                    .event("system")
+                    .data(String::from_utf8_lossy(text)),
+                // The client is too slow (disconnect policy)
+                (Frame::Close(..), _) => return None,
+                _ => continue,
+            };
+
+            return Some((Ok(event), (gate, guard)));
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Register the subscriptions of the session and replay the changes it missed
+fn sse_connected(
+    id: usize,
+    session: SessionInfo,
+    last_event_id: Option<(String, Position)>,
+    state: &ServerState,
+) {
+    // The forwarder cannot send any change until the subscriptions are registered
+    // and the changes missed replayed, otherwise some could be lost or duplicated.
+    let history = state.history.lock().unwrap();
+
+    for sub in &session.watch_for {
+        state.register(id, &session, Arc::clone(sub));
+    }
+
+    if let Some((last_event_id, position)) = last_event_id {
+        match history.since(position) {
+            Some(events) => {
+                let subscribers: Vec<Arc<Subscriber>> = session
+                    .watch_for
+                    .iter()
+                    .map(|sub| Arc::new(session.subscriber(id, Arc::clone(sub))))
+                    .collect();
+                let mut batches = state.batches.lock().unwrap();
+                websocket::replay(&events, &subscribers, history.open(), &mut batches);
+            }
+            None => {
+                info!("SSE: cannot resume client {} from {}", id, last_event_id);
+                // serde_json::to_string cannot fail on our own enum
+                let message = SystemMessage::ResumeGap { last_event_id };
+                session
+                    .gate
+                    .send(Frame::text(serde_json::to_string(&message).unwrap()));
+            }
+        }
+    }
+
+    state.clients.insert(id, session);
+}
+
+/// Remove the client and its subscriptions once its stream is dropped
+struct SseSession {
+    id: usize,
+    state: Arc<ServerState>,
+}
+
+impl Drop for SseSession {
+    fn drop(&mut self) {
+        trace!("SSE: client disconnected: {}", self.id);
+        self.state.unregister_all(self.id);
+    }
+}
@@ -0,0 +1,179 @@
+use super::{
+    gate::Gate,
+    ws_utils::{Encoding, MessageFormat, WsWatchFor},
+};
+
+use crate::{
+    cdc::event::{ChangeEvent, ChangeKind},
+    utils::specific_filter,
+    COLUMNS,
+};
+
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+const KINDS: [ChangeKind; 3] = [ChangeKind::Insert, ChangeKind::Update, ChangeKind::Delete];
+
+/// A subscription of a client, with everything needed to send it the messages
+pub struct Subscriber {
+    pub client: usize,
+    pub gate: Gate,
+    pub format: MessageFormat,
+    pub encoding: Encoding,
+    /// Are the changes batched by transaction
+    pub tx: bool,
+    /// Are the messages tagged with the subscription's id (shared by the session)
+    pub tagged: Arc<AtomicBool>,
+    pub watch_for: Arc<WsWatchFor>,
+}
+
+/// Identify a subscription: the id of the client and of the subscription
+type SubKey = (usize, String);
+
+/// Column and (normalized) value of the equality filter a subscription is indexed on
+type EqKey = (String, String);
+
+/// Subscriptions to a (table, op)
+#[derive(Default)]
+struct Subscribers {
+    /// Subscriptions which must be checked for every change
+    all: HashMap<SubKey, Arc<Subscriber>>,
+    /// Subscriptions having an equality filter: column -> value -> subscriptions
+    eq: HashMap<String, HashMap<String, HashMap<SubKey, Arc<Subscriber>>>>,
+}
+
+/// Index of the subscriptions keyed by (table, op), the subscriptions with an
+/// equality filter (`host_uuid.eq.X`) are also indexed by the value they're waiting for.
+///
+/// Being sharded, the forwarder and the clients (un)subscribing only lock a part of it.
+#[derive(Default)]
+pub struct SubscriptionIndex {
+    subscribers: DashMap<(String, ChangeKind), Subscribers>,
+    /// Where each subscription has been indexed, to remove it without searching for it
+    locations: DashMap<SubKey, Option<EqKey>>,
+}
+
+impl SubscriptionIndex {
+    /// Add the subscription to the index, for every change types it listen to
+    pub fn insert(&self, subscriber: Subscriber) {
+        let subscriber = Arc::new(subscriber);
+        let watch_for = &subscriber.watch_for;
+        let key = (subscriber.client, watch_for.id.clone());
+        let eq = eq_key(watch_for);
+
+        for kind in KINDS {
+            if !has_bit!(watch_for.change_flag, kind.flag()) {
+                continue;
+            }
+
+            let mut subs = self
+                .subscribers
+                .entry((watch_for.change_table.clone(), kind))
+                .or_default();
+            match &eq {
+                Some((column, value)) => subs
+                    .eq
+                    .entry(column.to_owned())
+                    .or_default()
+                    .entry(value.to_owned())
+                    .or_default()
+                    .insert(key.clone(), Arc::clone(&subscriber)),
+                None => subs.all.insert(key.clone(), Arc::clone(&subscriber)),
+            };
+        }
+
+        self.locations.insert(key, eq);
+    }
+
+    /// Remove the subscription of the client from the index
+    pub fn remove(&self, client: usize, watch_for: &WsWatchFor) {
+        let key = (client, watch_for.id.clone());
+        let eq = match self.locations.remove(&key) {
+            Some((_, eq)) => eq,
+            None => return,
+        };
+
+        for kind in KINDS {
+            if !has_bit!(watch_for.change_flag, kind.flag()) {
+                continue;
+            }
+
+            let table_key = (watch_for.change_table.clone(), kind);
+            if let Some(mut subs) = self.subscribers.get_mut(&table_key) {
+                match &eq {
+                    Some((column, value)) => {
+                        if let Some(values) = subs.eq.get_mut(column) {
+                            if let Some(keys) = values.get_mut(value) {
+                                keys.remove(&key);
+                                if keys.is_empty() {
+                                    values.remove(value);
+                                }
+                            }
+                            if values.is_empty() {
+                                subs.eq.remove(column);
+                            }
+                        }
+                    }
+                    None => {
+                        subs.all.remove(&key);
+                    }
+                }
+            }
+            // Don't keep the (table, op) around if nobody listen to it anymore
+            self.subscribers.remove_if(&table_key, |_, subs| {
+                subs.all.is_empty() && subs.eq.is_empty()
+            });
+        }
+    }
+
+    /// Get the subscriptions which may match the change: those to its table and
+    /// op, whose equality filter (if any) match. Their filter must still be checked.
+    pub fn candidates(&self, event: &ChangeEvent) -> Vec<Arc<Subscriber>> {
+        let subs = match self.subscribers.get(&(event.table.clone(), event.kind)) {
+            Some(subs) => subs,
+            None => return Vec::new(),
+        };
+
+        let mut candidates: Vec<Arc<Subscriber>> = subs.all.values().cloned().collect();
+        for (column, values) in &subs.eq {
+            let targeted = match event.column(column) {
+                // Like in SQL, nothing is equal to NULL
+                Some(col) if !col.value.is_null() => col,
+                _ => continue,
+            };
+
+            match specific_filter::column_index_value(targeted) {
+                Some(value) => {
+                    if let Some(keys) = values.get(&value) {
+                        candidates.extend(keys.values().cloned());
+                    }
+                }
+                // The value cannot be normalized (the type of the column is not the one
+                // expected), fallback to checking every subscriptions on that column.
+                None => candidates.extend(values.values().flat_map(|keys| keys.values().cloned())),
+            }
+        }
+
+        candidates
+    }
+}
+
+/// Get the column and normalized value the subscription can be indexed on, if any
+fn eq_key(watch_for: &WsWatchFor) -> Option<EqKey> {
+    let filter = watch_for.specific.as_ref()?.eq_filter()?;
+    let value = match &filter.value {
+        specific_filter::DataType::String(value) => value,
+        _ => return None,
+    };
+
+    // The value is normalized depending on the column's type, which must be known
+    let columns = COLUMNS.read().unwrap();
+    let table = columns.get(&watch_for.change_table)?;
+    let idx = table.columns.iter().position(|c| c == &filter.column)?;
+    let value = specific_filter::index_value(table.types.get(idx)?, value)?;
+
+    Some((filter.column.clone(), value))
+}
@@ -1,167 +1,365 @@
 #[cfg(feature = "auth")]
 use super::auth::{self, AuthInfo};
 
-use crate::{
-    api::ws_utils::{DELETE, UPDATE},
-    ID_COUNTER,
-};
+use crate::{CONFIG, ID_COUNTER};
 
 use axum::{
-    extract::{
-        ws::{Message, WebSocket},
-        Query, WebSocketUpgrade,
-    },
+    body::Body,
+    extract::{Query, Request},
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
     response::Response,
     Extension,
 };
-use futures::{stream::SplitStream, FutureExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use sproot::apierrors::ApiError;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::{
+    fmt::Display,
+    sync::{atomic::AtomicBool, Arc},
+};
+use yawc::{
+    close::CloseCode, CompressionLevel, Frame as WsFrame, OpCode, Options, UpgradeFut, WebSocket,
+};
 
 use super::{
-    query,
-    ws_utils::{ServerState, SessionInfo, WsWatchFor, INSERT},
+    control,
+    gate::{Frame, Gate},
+    query::{self, SessionParams},
+    ws_utils::{Encoding, ServerState, SessionInfo},
 };
 
+/// Max size of the messages of the clients, which only send control messages
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
 pub async fn accept_conn(
     #[cfg(feature = "auth")] auth: AuthInfo,
     Extension(state): Extension<Arc<ServerState>>,
-    Query(params): Query<HashMap<String, String>>,
-    ws: WebSocketUpgrade,
+    Query(params): Query<Vec<(String, String)>>,
+    mut req: Request,
 ) -> Result<Response, ApiError> {
-    // Extract the query params or return a bad request
-    let query = match params.get("query") {
-        Some(q) => q,
-        None => {
-            return Err(ApiError::ExplicitError(String::from(
-                "missing the query params",
-            )))
-        }
-    };
-
-    // Construct the watch_for from the query and if error, bad request
-    let watch_for = query::parse_ws_query(query)?;
+    // Parse the subscriptions and options of the session, if error, bad request
+    let SessionParams {
+        watch_for,
+        format,
+        encoding,
+        system,
+        tx: batch,
+    } = query::parse_session_params(&params)?;
 
     #[cfg(feature = "auth")]
     {
         if !auth.is_admin {
-            if watch_for.specific.is_none() {
-                return Err(ApiError::InvalidRequestError(None));
-            }
-
-            let specific = watch_for.specific.clone().unwrap();
-            auth::restrict_auth(auth, specific).await?;
+            auth::restrict_subscriptions(&auth, &watch_for).await?;
         }
     }
 
-    Ok(ws.on_upgrade(|socket: WebSocket| async {
+    // Negotiate the sub-protocol and the compression (permessage-deflate)
+    let compression = CONFIG.ws_compression.then_some(CONFIG.ws_compression_level);
+    let (response, upgrade, protocol) =
+        upgrade(&mut req, compression).map_err(ApiError::ExplicitError)?;
+
+    // The param take precedence over the negotiated sub-protocol
+    let encoding = encoding.or(protocol).unwrap_or_default();
+
+    tokio::task::spawn(async move {
+        let (user_ws_tx, user_ws_rx) = match upgrade.await {
+            Ok(ws) => ws.split(),
+            Err(err) => {
+                error!("Websocket: upgrade failed: {}", err);
+                return;
+            }
+        };
+
         let id = ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         trace!("Websocket: client connected: {}", id);
 
-        // Split the socket into a sender and receive of messages.
-        let (user_ws_tx, user_ws_rx) = socket.split();
+        // Use a bounded queue (client_queue_size) to handle buffering and flushing of messages to the websocket.
+        let tx = Gate::new(id);
+        tokio::task::spawn(ws_writer(id, tx.clone(), user_ws_tx, Arc::clone(&state)));
 
-        // Use an bounded channel (256) to handle buffering and flushing of messages to the websocket.
-        let (tx, rx) = mpsc::unbounded_channel();
-        let rx = UnboundedReceiverStream::new(rx);
-        tokio::task::spawn(rx.forward(user_ws_tx).map(|result| {
-            if let Err(err) = result {
-                error!("Websocket: send error for: {}", err);
-            }
-        }));
+        ws_connected(
+            #[cfg(feature = "auth")]
+            auth,
+            id,
+            SessionInfo {
+                gate: tx,
+                tagged: Arc::new(AtomicBool::new(watch_for.len() > 1)),
+                watch_for: watch_for.into_iter().map(Arc::new).collect(),
+                format,
+                encoding,
+                system,
+                tx: batch,
+            },
+            user_ws_rx,
+            state,
+        )
+        .await;
+    });
 
-        ws_connected(id, tx, user_ws_rx, watch_for, state).await;
-    }))
+    Ok(response)
+}
+
+/// Accept the websocket upgrade request, negotiating the sub-protocol (the first
+/// encoding asked by the client) and the compression at the level, if enabled.
+fn upgrade(
+    req: &mut Request,
+    compression: Option<u32>,
+) -> Result<(Response, UpgradeFut, Option<Encoding>), String> {
+    let mut options = Options::default()
+        .with_max_payload_read(MAX_MESSAGE_SIZE)
+        .with_utf8();
+    if let Some(level) = compression {
+        // Each message is compressed on its own, so that neither side
+        // has to keep a compression window per connection.
+        options = options
+            .with_compression_level(CompressionLevel::new(level))
+            .server_no_context_takeover()
+            .client_no_context_takeover();
+    }
+
+    let protocol = req
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|asked| Encoding::NAMES.iter().find(|&&name| name == asked.trim()))
+        .copied();
+
+    let (response, upgrade) = WebSocket::upgrade_with_options(&mut *req, options)
+        .map_err(|err| format!("invalid websocket upgrade request: {}", err))?;
+    let mut response = response.map(Body::new);
+    if let Some(protocol) = protocol {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+    }
+
+    Ok((response, upgrade, protocol.and_then(Encoding::from_name)))
+}
+
+/// Write the frames of the gate to the websocket until it's closed
+async fn ws_writer<S>(id: usize, gate: Gate, mut user_ws_tx: S, state: Arc<ServerState>)
+where
+    S: Sink<WsFrame> + Unpin,
+    S::Error: Display,
+{
+    while let Some((frame, _)) = gate.recv().await {
+        let frame = match frame {
+            Frame::Text(text) => WsFrame::text(text),
+            Frame::Binary(data) => WsFrame::binary(data),
+            Frame::Close(code, reason) => {
+                // The gate is closed, stop forwarding changes to the client right away
+                // instead of once it answered the close frame (if it ever does).
+                state.unregister_all(id);
+                WsFrame::close(CloseCode::from(code), reason)
+            }
+        };
+        let close = frame.opcode() == OpCode::Close;
+        // Only flush once every queued frames are written
+        let result = match user_ws_tx.feed(frame).await {
+            Ok(()) if gate.queued() == 0 => user_ws_tx.flush().await,
+            result => result,
+        };
+        if let Err(err) = result {
+            error!("Websocket: send error for: {}", err);
+            // Don't keep queuing messages for a client we can't write to
+            gate.close();
+            state.unregister_all(id);
+            return;
+        }
+        if close {
+            return;
+        }
+    }
 }
 
 async fn ws_connected(
+    #[cfg(feature = "auth")] auth: AuthInfo,
     id: usize,
-    tx: UnboundedSender<Result<Message, axum::Error>>,
-    mut user_ws_rx: SplitStream<WebSocket>,
-    watch_for: WsWatchFor,
+    session: SessionInfo,
+    mut user_ws_rx: impl Stream<Item = WsFrame> + Unpin,
     state: Arc<ServerState>,
 ) {
-    let change_flag = watch_for.change_flag;
-    let change_table = watch_for.change_table.to_owned();
-    // Save the sender in our list of connected clients.
-    state.clients.write().unwrap().insert(
-        id,
-        SessionInfo {
-            gate: tx.clone(),
-            watch_for,
-        },
-    );
-
-    // Insert in the right category depending on the ChangeType
-    if has_bit!(change_flag, INSERT) {
-        state
-            .inserts
-            .write()
-            .unwrap()
-            .entry(change_table.clone())
-            .or_default()
-            .insert(id);
-    }
-    if has_bit!(change_flag, UPDATE) {
-        state
-            .updates
-            .write()
-            .unwrap()
-            .entry(change_table.clone())
-            .or_default()
-            .insert(id);
-    }
-    if has_bit!(change_flag, DELETE) {
-        state
-            .deletes
-            .write()
-            .unwrap()
-            .entry(change_table)
-            .or_default()
-            .insert(id);
+    // Index the subscriptions so that the forwarder find them
+    for sub in &session.watch_for {
+        state.register(id, &session, Arc::clone(sub));
     }
+    // Save the sender in our list of connected clients.
+    let tx = session.gate.clone();
+    state.clients.insert(id, session);
 
-    while let Some(event) = user_ws_rx.next().await {
-        match event {
-            Ok(payload) => {
-                debug!("Websocket: msg: {:?}", payload);
-                if let Message::Close(_) = payload {
-                    info!("Websocket: client closed");
-                    break;
-                }
-            }
-            Err(err) => {
-                error!("Websocket: error: {}", err);
-                break;
+    // The pings and the close frames are answered by yawc, the stream
+    // ending once the connection is closed (or on error).
+    while let Some(frame) = user_ws_rx.next().await {
+        match frame.opcode() {
+            // Text messages are the control messages (subscribe, unsubscribe, ...)
+            OpCode::Text => {
+                let text = frame.as_str();
+                debug!("Websocket: msg: {}", text);
+                control::handle_control(
+                    #[cfg(feature = "auth")]
+                    &auth,
+                    id,
+                    text,
+                    &tx,
+                    &state,
+                )
+                .await
             }
+            OpCode::Close => info!("Websocket: client closed"),
+            _ => {}
         }
     }
 
-    ws_disconnected(id, state, change_flag);
+    ws_disconnected(id, state);
 }
 
-fn ws_disconnected(id: usize, state: Arc<ServerState>, change_flag: u8) {
+fn ws_disconnected(id: usize, state: Arc<ServerState>) {
     trace!("Websocket: client disconnected: {}", id);
-    // Stream closed up, so remove from the user list
-    state.clients.write().unwrap().remove(&id);
-
-    if has_bit!(change_flag, INSERT) {
-        // For each table entries, remove the id of the ws_session
-        for list_sessions in state.inserts.write().unwrap().values_mut() {
-            // Even if the event.id is not in the list_sessions, it will try
-            list_sessions.remove(&id);
+    // Stream closed up, so remove from the user list and its subscriptions
+    state.unregister_all(id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{api::gate::TOO_BIG_CLOSE_CODE, utils::config::SlowConsumerPolicy};
+
+    use axum::{routing::get, Router};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use yawc::TcpWebSocket;
+
+    /// Serve a websocket echoing the text messages of the clients through a
+    /// gate, closing the connection (Message Too Big) when asked to.
+    async fn echo_server() -> SocketAddr {
+        async fn echo(mut req: Request) -> Response {
+            let (response, upgrade, _) = upgrade(&mut req, Some(6)).unwrap();
+            tokio::spawn(async move {
+                let (sender, mut receiver) = upgrade.await.unwrap().split();
+                let gate = Gate::with_limits(0, 16, SlowConsumerPolicy::Disconnect);
+                let state = Arc::new(ServerState::default());
+                tokio::spawn(ws_writer(0, gate.clone(), sender, state));
+                while let Some(frame) = receiver.next().await {
+                    match frame.opcode() {
+                        OpCode::Text if frame.as_str() == "close" => {
+                            gate.send_control(Frame::Close(TOO_BIG_CLOSE_CODE, "too big"))
+                        }
+                        OpCode::Text => gate.send(Frame::text(frame.as_str().to_owned())),
+                        _ => continue,
+                    };
+                }
+            });
+            response
         }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/ws", get(echo));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
     }
-    if has_bit!(change_flag, UPDATE) {
-        for list_sessions in state.updates.write().unwrap().values_mut() {
-            list_sessions.remove(&id);
+
+    /// Connect to the server with yawc, compressing the messages
+    async fn client(addr: SocketAddr) -> TcpWebSocket {
+        let options = Options::default().with_compression_level(CompressionLevel::default());
+        let url = format!("ws://{}/ws", addr).parse().unwrap();
+        WebSocket::connect(url).with_options(options).await.unwrap()
+    }
+
+    /// Send an upgrade request with the extra headers, returning the
+    /// connection and the headers of the response (lowercased).
+    async fn handshake(addr: SocketAddr, headers: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            addr, headers
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
         }
+        (stream, String::from_utf8(response).unwrap().to_lowercase())
     }
-    if has_bit!(change_flag, DELETE) {
-        for list_sessions in state.deletes.write().unwrap().values_mut() {
-            list_sessions.remove(&id);
+
+    /// Send a text frame (masked with a zero key), returning the first byte and the data of the answer
+    async fn echo_raw(stream: &mut TcpStream, text: &str) -> (u8, Vec<u8>) {
+        let mut frame = vec![0x81, 0x80 | 126];
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(text.as_bytes());
+        stream.write_all(&frame).await.unwrap();
+
+        let first = stream.read_u8().await.unwrap();
+        let len = match stream.read_u8().await.unwrap() {
+            126 => stream.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut data = vec![0; len];
+        stream.read_exact(&mut data).await.unwrap();
+        (first, data)
+    }
+
+    #[tokio::test]
+    async fn compressed_messages() {
+        let addr = echo_server().await;
+        let (mut stream, response) = handshake(
+            addr,
+            "Sec-WebSocket-Protocol: cbor, msgpack\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n",
+        )
+        .await;
+        assert!(response.starts_with("http/1.1 101"));
+        assert!(response.contains("sec-websocket-protocol: cbor\r\n"));
+        assert!(response.contains(
+            "sec-websocket-extensions: permessage-deflate; server_no_context_takeover; client_no_context_takeover\r\n"
+        ));
+
+        // FIN + RSV1 + Text, smaller than the message
+        let text = r#"{"table":"hosts"}"#.repeat(100);
+        let (first, data) = echo_raw(&mut stream, &text).await;
+        assert_eq!(first, 0xC1);
+        assert!(data.len() < text.len());
+
+        // And decompressed by the clients
+        let mut client = client(addr).await;
+        for text in [String::from("small"), text] {
+            client.send(WsFrame::text(text.clone())).await.unwrap();
+            let frame = client.next().await.unwrap();
+            assert_eq!(frame.opcode(), OpCode::Text);
+            assert_eq!(frame.as_str(), text);
         }
     }
+
+    #[tokio::test]
+    async fn uncompressed_messages() {
+        let addr = echo_server().await;
+        let (mut stream, response) = handshake(addr, "").await;
+        assert!(response.starts_with("http/1.1 101"));
+        assert!(!response.contains("sec-websocket-extensions"));
+        assert!(!response.contains("sec-websocket-protocol"));
+
+        let text = r#"{"table":"hosts"}"#.repeat(100);
+        let (first, data) = echo_raw(&mut stream, &text).await;
+        assert_eq!(first, 0x81);
+        assert_eq!(data, text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn close_code() {
+        let addr = echo_server().await;
+        let mut client = client(addr).await;
+        client.send(WsFrame::text("close")).await.unwrap();
+        let frame = client.next().await.unwrap();
+        assert_eq!(frame.opcode(), OpCode::Close);
+        assert_eq!(frame.close_code(), Some(CloseCode::Size));
+        assert_eq!(frame.close_reason().unwrap(), Some("too big"));
+    }
 }
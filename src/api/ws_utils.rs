@@ -1,38 +1,139 @@
-use crate::utils::specific_filter::SpecificFilter;
-
-use axum::extract::ws::Message;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, RwLock},
+use super::{
+    gate::{Frame, Gate},
+    history::History,
+    subscriptions::{Subscriber, SubscriptionIndex},
 };
-use tokio::sync::mpsc;
+
+use crate::{forwarder::websocket::Batches, utils::specific_filter::FilterExpr};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
 
 pub const INSERT: u8 = 1 << 1;
 pub const UPDATE: u8 = 1 << 2;
 pub const DELETE: u8 = 1 << 3;
 
 pub struct SessionInfo {
-    pub gate: mpsc::UnboundedSender<Result<Message, axum::Error>>,
-    pub watch_for: WsWatchFor,
+    pub gate: Gate,
+    pub watch_for: Vec<Arc<WsWatchFor>>,
+    pub format: MessageFormat,
+    pub encoding: Encoding,
+    /// Does the client want to receive the SystemMessages
+    pub system: bool,
+    /// Does the client want the changes of a transaction in a single message
+    pub tx: bool,
+    /// Are the messages tagged with the subscription they matched, only once the
+    /// session has multiple subscriptions or used the control messages.
+    pub tagged: Arc<AtomicBool>,
+}
+
+impl SessionInfo {
+    /// Get the Subscriber of the session (id) for one of its subscriptions
+    pub fn subscriber(&self, id: usize, watch_for: Arc<WsWatchFor>) -> Subscriber {
+        Subscriber {
+            client: id,
+            gate: self.gate.clone(),
+            format: self.format,
+            encoding: self.encoding,
+            tx: self.tx,
+            tagged: Arc::clone(&self.tagged),
+            watch_for,
+        }
+    }
+}
+
+/// Encoding of the messages sent to a client, selected with the `encoding`
+/// param or negotiated using the Sec-WebSocket-Protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Encoding {
+    /// JSON text messages
+    #[default]
+    Json,
+    /// MessagePack binary messages
+    Msgpack,
+    /// CBOR binary messages
+    Cbor,
+}
+
+impl Encoding {
+    /// Name of the encodings, also used as websocket sub-protocols
+    pub const NAMES: [&'static str; 3] = ["json", "msgpack", "cbor"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::Msgpack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+}
+
+/// Shape of the messages sent to a client, selected with the `format` param
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// The change as wal2json would have emitted it (legacy)
+    #[default]
+    Wal2json,
+    /// Our own versioned envelope: {v, table, op, lsn, commit_ts, new, old}
+    Envelope,
+}
+
+impl MessageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wal2json" => Some(MessageFormat::Wal2json),
+            "envelope" => Some(MessageFormat::Envelope),
+            _ => None,
+        }
+    }
+}
+
+/// Messages sent by pgcdc itself (not a change) to the clients which asked for them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SystemMessage {
+    /// The replication stream has been lost, no change will be received until it resume
+    StreamDown {
+        since: DateTime<Utc>,
+        /// Position up to which the changes have been forwarded
+        last_lsn: String,
+    },
+    /// The replication stream is back
+    StreamResumed {
+        down_since: DateTime<Utc>,
+        downtime_ms: i64,
+        last_lsn: String,
+        /// Position from which the stream restarted
+        resume_lsn: String,
+        /// True if the changes made while down are lost (temporary slot)
+        gap: bool,
+    },
+    /// The tables and columns have been detected again (after a reconnection)
+    SchemaReloaded { tables: Vec<String> },
+    /// (SSE only) The changes since the Last-Event-ID cannot all be replayed,
+    /// the ones still known (if any) are not sent either.
+    ResumeGap { last_event_id: String },
 }
 
 /// Our state of currently connected clients.
 ///
 /// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-type Clients = Arc<RwLock<HashMap<usize, SessionInfo>>>;
+/// - Value is their SessionInfo (sender and subscriptions)
+type Clients = Arc<DashMap<usize, SessionInfo>>;
 
-/// Our state of currently connected clients listening for a particular table.
-///
-/// - Key is the table name
-/// - Value is a AHashSet containing all the client's id listening to that table.
-type TypeList = Arc<RwLock<HashMap<String, HashSet<usize>>>>;
-
-/// Contains info for what does the Ws is listening to
+/// Contains info for what does the Ws is listening to (one subscription)
 pub struct WsWatchFor {
+    /// Identify the subscription, messages matching it are tagged with it
+    pub id: String,
     pub change_table: String,
     pub change_flag: u8,
-    pub specific: Option<SpecificFilter>,
+    pub specific: Option<FilterExpr>,
+    /// Columns to send (the keys are always sent), all if None
+    pub select: Option<Vec<String>>,
 }
 
 pub fn apply_flag(flag: &mut u8, ctype: &str) {
@@ -61,7 +162,49 @@ pub fn apply_flag(flag: &mut u8, ctype: &str) {
 #[derive(Default, Clone)]
 pub struct ServerState {
     pub clients: Clients,
-    pub inserts: TypeList,
-    pub updates: TypeList,
-    pub deletes: TypeList,
+    /// Subscriptions of the clients, indexed for the forwarder
+    pub subscriptions: Arc<SubscriptionIndex>,
+    /// Last changes forwarded, for the SSE clients to resume from
+    pub history: Arc<Mutex<History>>,
+    /// Changes of the open transaction, for the clients in tx mode.
+    /// Only locked while holding the history.
+    pub batches: Arc<Mutex<Batches>>,
+}
+
+impl ServerState {
+    /// Register the subscription of the session id so that it get the changes matching it
+    pub fn register(&self, id: usize, session: &SessionInfo, watch_for: Arc<WsWatchFor>) {
+        self.subscriptions.insert(session.subscriber(id, watch_for));
+    }
+
+    /// Remove the subscription of the session id
+    pub fn unregister(&self, id: usize, watch_for: &WsWatchFor) {
+        self.subscriptions.remove(id, watch_for);
+    }
+
+    /// Send the system message to every connected clients which asked for them
+    pub fn broadcast(&self, message: &SystemMessage) {
+        // serde_json::to_string cannot fail on our own enum
+        let message = Frame::text(serde_json::to_string(message).unwrap());
+        for client in self.clients.iter().filter(|client| client.system) {
+            client.gate.send(message.clone());
+        }
+    }
+
+    /// Remove the session id from the clients and all of its subscriptions
+    pub fn unregister_all(&self, id: usize) {
+        if let Some((_, session)) = self.clients.remove(&id) {
+            session.gate.close();
+            if session.gate.dropped() > 0 {
+                info!(
+                    "Websocket: client {} dropped {} messages for being too slow",
+                    id,
+                    session.gate.dropped()
+                );
+            }
+            for sub in &session.watch_for {
+                self.unregister(id, sub);
+            }
+        }
+    }
 }
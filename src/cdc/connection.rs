@@ -1,3 +1,5 @@
+use super::CdcError;
+
 use crate::CONFIG;
 
 use openssl::ssl::{SslConnector, SslMethod};
@@ -5,7 +7,7 @@ use postgres_openssl::MakeTlsConnector;
 use tokio_postgres::{Client, NoTls};
 
 /// Connects to the Postgres server (using conn_string for server info)
-pub async fn db_client_start() -> Client {
+pub async fn db_client_start() -> Result<Client, CdcError> {
     let conn_string =
         format!(
         "host={} user={} dbname={} replication=database password={} sslmode={} connect_timeout=10",
@@ -27,7 +29,7 @@ pub async fn db_client_start() -> Client {
                 Ok((rc, rco)) => (rc, rco),
                 Err(e) => {
                     error!("Postgres: connection failed: {}, {:?}", e, e.as_db_error());
-                    std::process::exit(1);
+                    return Err(e.into());
                 }
             };
 
@@ -46,7 +48,7 @@ pub async fn db_client_start() -> Client {
                 Ok((rc, rco)) => (rc, rco),
                 Err(e) => {
                     error!("Postgres: connection failed: {}", e);
-                    std::process::exit(1);
+                    return Err(e.into());
                 }
             };
 
@@ -62,5 +64,5 @@ pub async fn db_client_start() -> Client {
     };
     info!("Postgres: connection established");
 
-    client
+    Ok(client)
 }
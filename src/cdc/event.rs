@@ -0,0 +1,137 @@
+use super::replication::{format_lsn, parse_lsn};
+
+use crate::api::ws_utils::{DELETE, INSERT, UPDATE};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Kind of change that happened to a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl ChangeKind {
+    /// Name of the change as used in the ws query (insert, update, delete)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Insert => "insert",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+        }
+    }
+
+    /// Get the change_flag bit corresponding to this kind
+    pub fn flag(&self) -> u8 {
+        match self {
+            ChangeKind::Insert => INSERT,
+            ChangeKind::Update => UPDATE,
+            ChangeKind::Delete => DELETE,
+        }
+    }
+}
+
+/// What the replication stream send to the forwarder
+#[derive(Debug)]
+pub enum StreamMessage {
+    Change(ChangeEvent),
+    /// Every change up to this WAL position has been sent, once the forwarder
+    /// reach it the position can be acknowledged to PostgreSQL.
+    Checkpoint(u64),
+    /// Every change of the transaction has been sent
+    Commit(Transaction),
+}
+
+/// Position of a change in the stream: the LSN of the commit of its transaction
+/// and its index in it, written `16/B374D848:3`.
+///
+/// Unlike the LSN of the changes (shared by a whole transaction with wal2json's
+/// format-version 1, and not ordered across transactions), it's unique and always
+/// increasing as the transactions are streamed in the order of their commit.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Position {
+    /// LSN of the commit (of its end with wal2json)
+    pub commit_lsn: u64,
+    /// Index of the change in the transaction
+    pub index: u32,
+}
+
+impl Position {
+    pub fn new(commit_lsn: u64, index: u32) -> Self {
+        Self { commit_lsn, index }
+    }
+
+    /// Parse a position as displayed (ex: 16/B374D848:3)
+    pub fn parse(position: &str) -> Option<Self> {
+        let (lsn, index) = position.split_once(':')?;
+        Some(Self::new(parse_lsn(lsn)?, index.parse().ok()?))
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", format_lsn(self.commit_lsn), self.index)
+    }
+}
+
+/// A committed transaction, sent after all of its changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub xid: Option<u32>,
+    pub commit_ts: Option<DateTime<Utc>>,
+    /// LSN of the commit
+    pub lsn: u64,
+}
+
+/// A column of a row: its name, (PostgreSQL) type and value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub type_name: String,
+    pub value: Value,
+}
+
+/// A single change decoded from the replication stream,
+/// independently of the output plugin used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub schema: String,
+    /// Table name (already resolved from the TimescaleDB's chunk name)
+    pub table: String,
+    pub kind: ChangeKind,
+    /// New values of the row (empty for deletes)
+    pub columns: Vec<Column>,
+    /// Key (replica identity) values of the row before the change
+    pub old_keys: Vec<Column>,
+    /// LSN of the change (or of its transaction, depending on the plugin)
+    pub lsn: u64,
+    /// Unique position of the change in the stream
+    #[serde(default)]
+    pub position: Position,
+    /// Commit timestamp of the transaction, when known
+    pub commit_ts: Option<DateTime<Utc>>,
+    /// Id of the transaction, when known
+    #[serde(default)]
+    pub xid: Option<u32>,
+}
+
+impl ChangeEvent {
+    /// Get a column from the new values of the row.
+    ///
+    /// Fallback to the old keys if it's not part of the new values, as for
+    /// deletes (which only carry the old keys) or unchanged TOASTed values.
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns
+            .iter()
+            .chain(self.old_keys.iter())
+            .find(|c| c.name == name)
+    }
+}
@@ -1,15 +1,107 @@
-use crate::TABLES;
+use self::{event::StreamMessage, pgoutput::PgOutputDecoder, wal2json::Wal2JsonDecoder};
+
 #[cfg(feature = "timescale")]
 use crate::TABLES_LOOKUP;
+use crate::{utils::config::OutputPlugin, COLUMNS, CONFIG, TABLES};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, Cursor},
+};
 use tokio_postgres::{Client, SimpleQueryMessage};
 
 pub mod connection;
+pub mod event;
+pub mod pgoutput;
+pub mod progress;
 pub mod replication;
+pub mod wal2json;
+
+/// Errors which can happen while setting up or running the replication
+#[derive(Debug)]
+pub enum CdcError {
+    /// The database could not be reached or refused a query, retrying may help
+    Postgres(tokio_postgres::Error),
+    /// The configuration (or the database setup) is invalid, retrying won't help
+    Config(String),
+}
+
+impl fmt::Display for CdcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdcError::Postgres(e) => write!(f, "{}", e),
+            CdcError::Config(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for CdcError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        CdcError::Postgres(e)
+    }
+}
+
+/// REPLICA IDENTITY of a table, which define what is part of the old image
+/// of the row on updates and deletes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicaIdentity {
+    /// The primary key (if any)
+    #[default]
+    Default,
+    /// The columns of a specific unique index
+    Index,
+    /// Every columns
+    Full,
+    /// Nothing at all
+    Nothing,
+}
+
+impl ReplicaIdentity {
+    /// Get the ReplicaIdentity from pg_class.relreplident
+    fn from_relreplident(relreplident: &str) -> Self {
+        match relreplident {
+            "i" => ReplicaIdentity::Index,
+            "f" => ReplicaIdentity::Full,
+            "n" => ReplicaIdentity::Nothing,
+            _ => ReplicaIdentity::Default,
+        }
+    }
+}
+
+/// Columns of a table, as found in the catalog
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableColumns {
+    pub columns: Vec<String>,
+    /// Types of the columns (in the same order)
+    pub types: Vec<String>,
+    /// Columns of the primary key
+    pub keys: Vec<String>,
+    pub replica_identity: ReplicaIdentity,
+    /// Columns part of the old image of updates and deletes
+    pub identity: Vec<String>,
+}
+
+/// Decode the output of a logical decoding plugin into ChangeEvents (and commits)
+pub trait Decoder: Send {
+    /// Decode the data of one XLogData message starting at the WAL position lsn
+    /// into its changes, followed by the commit if it ends a transaction.
+    fn decode(&mut self, lsn: u64, buf: &mut Cursor<Bytes>) -> io::Result<Vec<StreamMessage>>;
+}
+
+/// Construct the Decoder for the output plugin defined in the config
+pub fn decoder() -> Box<dyn Decoder> {
+    match CONFIG.output_plugin {
+        OutputPlugin::Wal2json => Box::new(Wal2JsonDecoder::new(CONFIG.wal2json_format_version)),
+        OutputPlugin::Pgoutput => Box::<PgOutputDecoder>::default(),
+    }
+}
 
 #[cfg(feature = "timescale")]
-pub fn extract_hyper_idx(table_name: &str) -> Result<i8, ()> {
+pub(crate) fn extract_hyper_idx(table_name: &str) -> Result<i8, ()> {
     let mut parts = table_name.splitn(4, '_');
     match parts.nth(2) {
         Some(val) => Ok(val.parse::<i8>().unwrap()),
@@ -17,9 +109,48 @@ pub fn extract_hyper_idx(table_name: &str) -> Result<i8, ()> {
     }
 }
 
+/// Get the table name from an &str, returning a String
+/// This is used due to TimescaleDB renaming the hypertable using a
+/// pattern '_hyper_' with some number and all. If we can't convert the pattern
+/// back to it's original table name, return the pattern name.
+#[cfg(feature = "timescale")]
+pub fn resolve_table_name(table_name: &str) -> String {
+    if table_name.starts_with("_hyper_") {
+        let idx = match extract_hyper_idx(table_name) {
+            Ok(idx) => idx,
+            Err(_) => {
+                error!(
+                    "Match table: table {} cannot be deconstructed into an idx",
+                    table_name
+                );
+                return table_name.to_owned();
+            }
+        };
+        // Get the table name from the index and return an owned String
+        // or continue the loop and skip this value if not found.
+        match TABLES_LOOKUP.read().unwrap().get(&idx) {
+            Some(val) => return val.to_owned(),
+            None => {
+                error!(
+                    "Match table: table not found inside using index: {}:{}",
+                    idx, table_name,
+                );
+                return table_name.to_owned();
+            }
+        }
+    }
+    table_name.to_owned()
+}
+
+#[cfg(not(feature = "timescale"))]
+pub fn resolve_table_name(table_name: &str) -> String {
+    table_name.to_owned()
+}
+
 #[async_trait]
 pub trait ExtConfig {
     async fn detect_tables(&self) {}
+    async fn detect_columns(&self) {}
     #[cfg(feature = "timescale")]
     async fn detect_lookup(&self) {}
 }
@@ -46,6 +177,66 @@ impl ExtConfig for Client {
         }
     }
 
+    /// Fill the global COLUMNS HashMap with the columns (keys and replica identity)
+    /// of the public tables, warning about the tables without any replica identity.
+    async fn detect_columns(&self) {
+        let query = "SELECT c.relname, a.attname, format_type(a.atttypid, a.atttypmod), COALESCE(a.attnum = ANY(i.indkey), false), c.relreplident, COALESCE(a.attnum = ANY(r.indkey), false) FROM pg_attribute a JOIN pg_class c ON c.oid = a.attrelid JOIN pg_namespace n ON n.oid = c.relnamespace LEFT JOIN pg_index i ON i.indrelid = c.oid AND i.indisprimary LEFT JOIN pg_index r ON r.indrelid = c.oid AND r.indisreplident WHERE n.nspname = 'public' AND c.relkind IN ('r', 'p') AND a.attnum > 0 AND NOT a.attisdropped ORDER BY c.relname, a.attnum;";
+        COLUMNS.write().unwrap().clear();
+
+        match self.simple_query(query).await {
+            Ok(res) => res.into_iter().for_each(|msg| {
+                if let SimpleQueryMessage::Row(row) = msg {
+                    if let (
+                        Some(table),
+                        Some(column),
+                        Some(type_name),
+                        Some(is_key),
+                        Some(relreplident),
+                        Some(is_ident),
+                    ) = (
+                        row.get(0),
+                        row.get(1),
+                        row.get(2),
+                        row.get(3),
+                        row.get(4),
+                        row.get(5),
+                    ) {
+                        let mut columns = COLUMNS.write().unwrap();
+                        let entry = columns.entry(table.to_owned()).or_default();
+                        entry.replica_identity = ReplicaIdentity::from_relreplident(relreplident);
+                        entry.columns.push(column.to_owned());
+                        entry.types.push(type_name.to_owned());
+                        if is_key == "t" {
+                            entry.keys.push(column.to_owned());
+                        }
+                        // Is the column part of the old image of the row
+                        let in_identity = match entry.replica_identity {
+                            ReplicaIdentity::Default => is_key == "t",
+                            ReplicaIdentity::Index => is_ident == "t",
+                            ReplicaIdentity::Full => true,
+                            ReplicaIdentity::Nothing => false,
+                        };
+                        if in_identity {
+                            entry.identity.push(column.to_owned());
+                        }
+                    }
+                }
+            }),
+            Err(err) => {
+                error!("Cannot check the columns, continuing without them: {}", err);
+            }
+        }
+
+        for (table, columns) in COLUMNS.read().unwrap().iter() {
+            if columns.identity.is_empty() {
+                warn!(
+                    "Table {} has no usable REPLICA IDENTITY ({:?}), its deletes won't match any filter and won't carry old values",
+                    table, columns.replica_identity
+                );
+            }
+        }
+    }
+
     #[cfg(feature = "timescale")]
     async fn detect_lookup(&self) {
         let query =
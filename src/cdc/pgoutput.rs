@@ -0,0 +1,661 @@
+//! Decoder for the built-in `pgoutput` logical replication protocol (proto_version 1).
+//!
+//! pgoutput stream each transaction as a sequence of binary messages (Begin, Relation,
+//! Insert/Update/Delete/Truncate, ..., Commit). Each Insert/Update/Delete is decoded
+//! into a ChangeEvent as soon as it's received (only committed transactions are sent).
+//!
+//! See https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html
+
+use super::{
+    event::{ChangeEvent, ChangeKind, Column, Position, StreamMessage, Transaction},
+    replication::TIME_SEC_CONVERSION,
+    resolve_table_name, Decoder,
+};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Cursor, Read},
+};
+
+const BEGIN_TAG: u8 = b'B';
+const COMMIT_TAG: u8 = b'C';
+const ORIGIN_TAG: u8 = b'O';
+const RELATION_TAG: u8 = b'R';
+const TYPE_TAG: u8 = b'Y';
+const INSERT_TAG: u8 = b'I';
+const UPDATE_TAG: u8 = b'U';
+const DELETE_TAG: u8 = b'D';
+const TRUNCATE_TAG: u8 = b'T';
+const MESSAGE_TAG: u8 = b'M';
+
+const TUPLE_NEW: u8 = b'N';
+const TUPLE_KEY: u8 = b'K';
+const TUPLE_OLD: u8 = b'O';
+
+const COL_NULL: u8 = b'n';
+const COL_UNCHANGED_TOAST: u8 = b'u';
+const COL_TEXT: u8 = b't';
+
+/// Column description as sent in a Relation message
+#[derive(Debug)]
+struct RelationColumn {
+    is_key: bool,
+    name: String,
+    type_oid: u32,
+}
+
+/// Relation (table) description, sent by the server before the first
+/// change of a relation (and every time its definition changes).
+#[derive(Debug)]
+struct Relation {
+    schema: String,
+    table: String,
+    columns: Vec<RelationColumn>,
+}
+
+/// One column's value inside a TupleData
+enum TupleValue {
+    Null,
+    UnchangedToast,
+    Text(String),
+}
+
+/// Stateful decoder, one per replication stream as the Relation
+/// messages are only sent once per connection.
+#[derive(Default)]
+pub struct PgOutputDecoder {
+    relations: HashMap<u32, Relation>,
+    types: HashMap<u32, String>,
+    xid: Option<u32>,
+    commit_ts: Option<DateTime<Utc>>,
+    /// Position of the next change of the transaction
+    position: Position,
+}
+
+impl Decoder for PgOutputDecoder {
+    /// Decode one pgoutput message, only Insert, Update and Delete produce a ChangeEvent.
+    fn decode(&mut self, lsn: u64, buf: &mut Cursor<Bytes>) -> io::Result<Vec<StreamMessage>> {
+        let event = match buf.read_u8()? {
+            BEGIN_TAG => {
+                // final_lsn (u64), commit timestamp (i64), xid (u32)
+                let final_lsn = buf.read_u64::<BigEndian>()?;
+                self.position = Position::new(final_lsn, 0);
+                let ts = buf.read_i64::<BigEndian>()?;
+                // The timestamp is in microseconds since 2000-01-01
+                self.commit_ts =
+                    DateTime::from_timestamp_micros(ts + TIME_SEC_CONVERSION as i64 * 1_000_000);
+                self.xid = Some(buf.read_u32::<BigEndian>()?);
+                return Ok(Vec::new());
+            }
+            COMMIT_TAG => {
+                // flags (u8), commit_lsn (u64), end_lsn (u64), commit timestamp (i64)
+                let _flags = buf.read_u8()?;
+                let commit_lsn = buf.read_u64::<BigEndian>()?;
+                return Ok(vec![StreamMessage::Commit(Transaction {
+                    xid: self.xid.take(),
+                    commit_ts: self.commit_ts.take(),
+                    lsn: commit_lsn,
+                })]);
+            }
+            RELATION_TAG => {
+                self.decode_relation(buf)?;
+                return Ok(Vec::new());
+            }
+            TYPE_TAG => {
+                let oid = buf.read_u32::<BigEndian>()?;
+                let _namespace = read_cstring(buf)?;
+                let name = read_cstring(buf)?;
+                self.types.insert(oid, name);
+                return Ok(Vec::new());
+            }
+            INSERT_TAG => {
+                let relation = self.relation(buf.read_u32::<BigEndian>()?)?;
+                expect_tag(buf, TUPLE_NEW)?;
+                let values = read_tuple(buf)?;
+                self.event(relation, ChangeKind::Insert, Some(&values), None, lsn)?
+            }
+            UPDATE_TAG => {
+                let relation = self.relation(buf.read_u32::<BigEndian>()?)?;
+                // The old tuple is only present if the key changed (K) or
+                // if the table is REPLICA IDENTITY FULL (O).
+                let old = match buf.read_u8()? {
+                    TUPLE_KEY | TUPLE_OLD => {
+                        let old = read_tuple(buf)?;
+                        expect_tag(buf, TUPLE_NEW)?;
+                        Some(old)
+                    }
+                    TUPLE_NEW => None,
+                    tag => return Err(invalid_data(format!("unexpected tuple tag {}", tag))),
+                };
+                let values = read_tuple(buf)?;
+                self.event(
+                    relation,
+                    ChangeKind::Update,
+                    Some(&values),
+                    old.as_deref(),
+                    lsn,
+                )?
+            }
+            DELETE_TAG => {
+                let relation = self.relation(buf.read_u32::<BigEndian>()?)?;
+                match buf.read_u8()? {
+                    TUPLE_KEY | TUPLE_OLD => {}
+                    tag => return Err(invalid_data(format!("unexpected tuple tag {}", tag))),
+                }
+                let old = read_tuple(buf)?;
+                self.event(relation, ChangeKind::Delete, None, Some(&old), lsn)?
+            }
+            TRUNCATE_TAG => {
+                // Truncate are not forwarded (as with wal2json), but still
+                // make sure we know about the relations being truncated.
+                let nrels = buf.read_u32::<BigEndian>()?;
+                let _options = buf.read_u8()?;
+                for _ in 0..nrels {
+                    let rel = &self.relations[&self.relation(buf.read_u32::<BigEndian>()?)?];
+                    trace!("PgOutput: truncate of {}.{} ignored", rel.schema, rel.table);
+                }
+                return Ok(Vec::new());
+            }
+            // Nothing we care about in those
+            ORIGIN_TAG | MESSAGE_TAG => return Ok(Vec::new()),
+            tag => {
+                return Err(invalid_data(format!("unknown message type `{}`", tag)));
+            }
+        };
+
+        self.position.index += 1;
+        Ok(vec![StreamMessage::Change(event)])
+    }
+}
+
+impl PgOutputDecoder {
+    fn decode_relation(&mut self, buf: &mut Cursor<Bytes>) -> io::Result<()> {
+        let oid = buf.read_u32::<BigEndian>()?;
+        let schema = read_cstring(buf)?;
+        let table = read_cstring(buf)?;
+        let _replica_identity = buf.read_u8()?;
+        let ncols = buf.read_u16::<BigEndian>()?;
+
+        let mut columns = Vec::with_capacity(ncols as usize);
+        for _ in 0..ncols {
+            let flags = buf.read_u8()?;
+            let name = read_cstring(buf)?;
+            let type_oid = buf.read_u32::<BigEndian>()?;
+            let _type_modifier = buf.read_i32::<BigEndian>()?;
+            columns.push(RelationColumn {
+                is_key: flags & 1 == 1,
+                name,
+                type_oid,
+            });
+        }
+
+        trace!("PgOutput: relation {} is {}.{}", oid, schema, table);
+        self.relations.insert(
+            oid,
+            Relation {
+                schema,
+                table,
+                columns,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Check that the relation is known and return its oid back
+    fn relation(&self, oid: u32) -> io::Result<u32> {
+        if !self.relations.contains_key(&oid) {
+            return Err(invalid_data(format!("unknown relation {}", oid)));
+        }
+        Ok(oid)
+    }
+
+    /// Build the ChangeEvent for a change on the relation
+    fn event(
+        &self,
+        relation: u32,
+        kind: ChangeKind,
+        values: Option<&[TupleValue]>,
+        old: Option<&[TupleValue]>,
+        lsn: u64,
+    ) -> io::Result<ChangeEvent> {
+        let rel = &self.relations[&relation];
+
+        let columns = match values {
+            Some(values) => {
+                if values.len() != rel.columns.len() {
+                    return Err(invalid_data(format!(
+                        "tuple has {} columns but relation {}.{} has {}",
+                        values.len(),
+                        rel.schema,
+                        rel.table,
+                        rel.columns.len()
+                    )));
+                }
+                // Like wal2json, unchanged TOASTed values are not part of the change
+                self.columns(rel, values, |_, value| {
+                    !matches!(value, TupleValue::UnchangedToast)
+                })
+            }
+            None => Vec::new(),
+        };
+
+        // With REPLICA IDENTITY FULL every column is part of the old tuple,
+        // otherwise only the key ones are filled (the others being null).
+        let old_keys = match (old, values) {
+            (Some(old), _) => self.columns(rel, old, |column, value| {
+                column.is_key || matches!(value, TupleValue::Text(_))
+            }),
+            // The old tuple is not sent for updates which didn't change the key,
+            // in which case the old key is the same as the new one (like wal2json).
+            (None, Some(values)) if kind == ChangeKind::Update => {
+                self.columns(rel, values, |column, _| column.is_key)
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(ChangeEvent {
+            schema: rel.schema.to_owned(),
+            table: resolve_table_name(&rel.table),
+            kind,
+            columns,
+            old_keys,
+            lsn,
+            position: self.position,
+            commit_ts: self.commit_ts,
+            xid: self.xid,
+        })
+    }
+
+    /// Convert the tuple's values into Columns, keeping only those accepted by keep
+    fn columns<F>(&self, rel: &Relation, values: &[TupleValue], keep: F) -> Vec<Column>
+    where
+        F: Fn(&RelationColumn, &TupleValue) -> bool,
+    {
+        rel.columns
+            .iter()
+            .zip(values)
+            .filter(|(column, value)| keep(column, value))
+            .map(|(column, value)| Column {
+                name: column.name.to_owned(),
+                type_name: self.type_name(column.type_oid),
+                value: to_json_value(column.type_oid, value),
+            })
+            .collect()
+    }
+
+    /// Get the name of a type from its oid, using the same naming as wal2json
+    fn type_name(&self, oid: u32) -> String {
+        let name = match oid {
+            16 => "boolean",
+            17 => "bytea",
+            18 => "char",
+            19 => "name",
+            20 => "bigint",
+            21 => "smallint",
+            23 => "integer",
+            25 => "text",
+            26 => "oid",
+            114 => "json",
+            650 => "cidr",
+            700 => "real",
+            701 => "double precision",
+            869 => "inet",
+            1042 => "character",
+            1043 => "character varying",
+            1082 => "date",
+            1083 => "time without time zone",
+            1114 => "timestamp without time zone",
+            1184 => "timestamp with time zone",
+            1186 => "interval",
+            1266 => "time with time zone",
+            1700 => "numeric",
+            2950 => "uuid",
+            3802 => "jsonb",
+            // Non built-in types are described by a Type message before being used
+            _ => {
+                return self
+                    .types
+                    .get(&oid)
+                    .cloned()
+                    .unwrap_or_else(|| String::from("unknown"))
+            }
+        };
+
+        name.to_owned()
+    }
+}
+
+/// Convert the text representation of a column into a JSON value,
+/// using numbers and booleans where wal2json would.
+fn to_json_value(type_oid: u32, value: &TupleValue) -> Value {
+    let text = match value {
+        TupleValue::Text(text) => text,
+        TupleValue::Null | TupleValue::UnchangedToast => return Value::Null,
+    };
+
+    match type_oid {
+        // bool
+        16 => Value::Bool(text == "t"),
+        // int8, int2, int4, oid
+        20 | 21 | 23 | 26 => text
+            .parse::<i64>()
+            .map_or_else(|_| Value::String(text.to_owned()), Value::from),
+        // float4, float8 (NaN and Infinity stay as strings)
+        700 | 701 => match text.parse::<f64>() {
+            Ok(val) if val.is_finite() => serde_json::Number::from_f64(val)
+                .map_or_else(|| Value::String(text.to_owned()), Value::Number),
+            _ => Value::String(text.to_owned()),
+        },
+        // numeric stays a string, as a f64 would lose its precision
+        _ => Value::String(text.to_owned()),
+    }
+}
+
+/// Read a TupleData structure
+fn read_tuple(buf: &mut Cursor<Bytes>) -> io::Result<Vec<TupleValue>> {
+    let ncols = buf.read_u16::<BigEndian>()?;
+    let mut values = Vec::with_capacity(ncols as usize);
+
+    for _ in 0..ncols {
+        let value = match buf.read_u8()? {
+            COL_NULL => TupleValue::Null,
+            COL_UNCHANGED_TOAST => TupleValue::UnchangedToast,
+            COL_TEXT => {
+                let len = buf.read_u32::<BigEndian>()?;
+                let mut data = vec![0; len as usize];
+                buf.read_exact(&mut data)?;
+                TupleValue::Text(String::from_utf8(data).map_err(|e| invalid_data(e.to_string()))?)
+            }
+            tag => return Err(invalid_data(format!("unknown column type `{}`", tag))),
+        };
+        values.push(value);
+    }
+
+    Ok(values)
+}
+
+/// Read a null-terminated string
+fn read_cstring(buf: &mut Cursor<Bytes>) -> io::Result<String> {
+    let mut data = Vec::new();
+    buf.read_until(0, &mut data)?;
+    if data.pop() != Some(0) {
+        return Err(invalid_data(String::from("unterminated string")));
+    }
+    String::from_utf8(data).map_err(|e| invalid_data(e.to_string()))
+}
+
+fn expect_tag(buf: &mut Cursor<Bytes>, expected: u8) -> io::Result<()> {
+    let tag = buf.read_u8()?;
+    if tag != expected {
+        return Err(invalid_data(format!(
+            "expected tuple tag {} but got {}",
+            expected, tag
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::{BufMut, BytesMut};
+
+    const RELATION_OID: u32 = 16384;
+
+    fn decode(decoder: &mut PgOutputDecoder, lsn: u64, msg: BytesMut) -> Vec<StreamMessage> {
+        decoder.decode(lsn, &mut Cursor::new(msg.freeze())).unwrap()
+    }
+
+    fn put_cstring(buf: &mut BytesMut, value: &str) {
+        buf.put_slice(value.as_bytes());
+        buf.put_u8(0);
+    }
+
+    /// Tuple of text values, None being a NULL and Some("u") an unchanged TOAST
+    fn put_tuple(buf: &mut BytesMut, values: &[Option<&str>]) {
+        buf.put_u16(values.len() as u16);
+        for value in values {
+            match value {
+                None => buf.put_u8(COL_NULL),
+                Some("u") => buf.put_u8(COL_UNCHANGED_TOAST),
+                Some(text) => {
+                    buf.put_u8(COL_TEXT);
+                    buf.put_u32(text.len() as u32);
+                    buf.put_slice(text.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn begin(xid: u32, final_lsn: u64) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(BEGIN_TAG);
+        buf.put_u64(final_lsn);
+        // 2022-01-01 00:00:00 UTC, in microseconds since 2000-01-01
+        buf.put_i64((1_640_995_200 - TIME_SEC_CONVERSION as i64) * 1_000_000);
+        buf.put_u32(xid);
+        buf
+    }
+
+    /// hosts(uuid uuid key, cpus integer, load numeric, notes text)
+    fn relation() -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(RELATION_TAG);
+        buf.put_u32(RELATION_OID);
+        put_cstring(&mut buf, "public");
+        put_cstring(&mut buf, "hosts");
+        buf.put_u8(b'd');
+        buf.put_u16(4);
+        for (flags, name, oid) in [
+            (1, "uuid", 2950),
+            (0, "cpus", 23),
+            (0, "load", 1700),
+            (0, "notes", 25),
+        ] {
+            buf.put_u8(flags);
+            put_cstring(&mut buf, name);
+            buf.put_u32(oid);
+            buf.put_i32(-1);
+        }
+        buf
+    }
+
+    fn change(tag: u8, tuples: &[(u8, &[Option<&str>])]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(tag);
+        buf.put_u32(RELATION_OID);
+        for (tuple_tag, values) in tuples {
+            buf.put_u8(*tuple_tag);
+            put_tuple(&mut buf, values);
+        }
+        buf
+    }
+
+    fn commit() -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(COMMIT_TAG);
+        buf.put_u8(0);
+        buf.put_u64(0x16B3800);
+        buf.put_u64(0x16B3830);
+        buf.put_i64(0);
+        buf
+    }
+
+    fn event(mut messages: Vec<StreamMessage>) -> ChangeEvent {
+        assert_eq!(messages.len(), 1);
+        match messages.pop() {
+            Some(StreamMessage::Change(event)) => event,
+            other => panic!("expected a change, got {:?}", other),
+        }
+    }
+
+    /// Decoder which already received the Begin and Relation messages
+    fn decoder() -> PgOutputDecoder {
+        let mut decoder = PgOutputDecoder::default();
+        assert!(decode(&mut decoder, 1, begin(735, 0x16B3800)).is_empty());
+        assert!(decode(&mut decoder, 2, relation()).is_empty());
+        decoder
+    }
+
+    const UUID: &str = "7d4e1b44-ed5e-4dbb-b6a3-35dbcc3c6a0e";
+
+    #[test]
+    fn insert() {
+        let mut decoder = decoder();
+        let event = event(decode(
+            &mut decoder,
+            3,
+            change(
+                INSERT_TAG,
+                &[(
+                    TUPLE_NEW,
+                    &[Some(UUID), Some("8"), Some("12345678901234567890.12"), None],
+                )],
+            ),
+        ));
+
+        assert_eq!(event.schema, "public");
+        assert_eq!(event.table, "hosts");
+        assert_eq!(event.kind, ChangeKind::Insert);
+        assert_eq!(event.lsn, 3);
+        // The position uses the final_lsn of the Begin message
+        assert_eq!(event.position, Position::new(0x16B3800, 0));
+        assert_eq!(event.xid, Some(735));
+        assert_eq!(
+            event.commit_ts.map(|ts| ts.to_rfc3339()).as_deref(),
+            Some("2022-01-01T00:00:00+00:00")
+        );
+
+        let columns: Vec<(&str, &str, &Value)> = event
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.type_name.as_str(), &c.value))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("uuid", "uuid", &Value::from(UUID)),
+                ("cpus", "integer", &Value::from(8)),
+                // numeric keeps its precision
+                ("load", "numeric", &Value::from("12345678901234567890.12")),
+                ("notes", "text", &Value::Null),
+            ]
+        );
+        assert!(event.old_keys.is_empty());
+    }
+
+    #[test]
+    fn positions() {
+        let mut decoder = decoder();
+        let insert = change(INSERT_TAG, &[(TUPLE_NEW, &[Some(UUID), None, None, None])]);
+        let first = event(decode(&mut decoder, 9, insert.clone()));
+        // Changes of a transaction are not always in the order of their LSN
+        let second = event(decode(&mut decoder, 3, insert.clone()));
+        assert_eq!(first.position.to_string(), "0/16B3800:0");
+        assert_eq!(second.position.to_string(), "0/16B3800:1");
+
+        decode(&mut decoder, 10, commit());
+        decode(&mut decoder, 11, begin(736, 0x16B3900));
+        let third = event(decode(&mut decoder, 2, insert));
+        assert_eq!(third.position, Position::new(0x16B3900, 0));
+    }
+
+    #[test]
+    fn update_with_old_tuple() {
+        let mut decoder = decoder();
+        let event = event(decode(
+            &mut decoder,
+            3,
+            change(
+                UPDATE_TAG,
+                &[
+                    (TUPLE_KEY, &[Some("old-uuid"), None, None, None]),
+                    (TUPLE_NEW, &[Some(UUID), Some("4"), Some("0.5"), Some("u")]),
+                ],
+            ),
+        ));
+
+        assert_eq!(event.kind, ChangeKind::Update);
+        // The unchanged TOASTed value is not part of the change
+        let names: Vec<&str> = event.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["uuid", "cpus", "load"]);
+        // Only the key is part of the old keys
+        assert_eq!(event.old_keys.len(), 1);
+        assert_eq!(event.old_keys[0].name, "uuid");
+        assert_eq!(event.old_keys[0].value, Value::from("old-uuid"));
+    }
+
+    #[test]
+    fn update_without_old_tuple() {
+        let mut decoder = decoder();
+        let event = event(decode(
+            &mut decoder,
+            3,
+            change(
+                UPDATE_TAG,
+                &[(TUPLE_NEW, &[Some(UUID), Some("4"), None, None])],
+            ),
+        ));
+
+        // The key didn't change, the old one is the new one
+        assert_eq!(event.old_keys.len(), 1);
+        assert_eq!(event.old_keys[0].value, Value::from(UUID));
+    }
+
+    #[test]
+    fn delete() {
+        let mut decoder = decoder();
+        let event = event(decode(
+            &mut decoder,
+            3,
+            change(DELETE_TAG, &[(TUPLE_KEY, &[Some(UUID), None, None, None])]),
+        ));
+
+        assert_eq!(event.kind, ChangeKind::Delete);
+        assert!(event.columns.is_empty());
+        assert_eq!(event.old_keys.len(), 1);
+        assert_eq!(event.old_keys[0].value, Value::from(UUID));
+    }
+
+    #[test]
+    fn commit_ends_the_transaction() {
+        let mut decoder = decoder();
+        let messages = decode(&mut decoder, 4, commit());
+
+        match messages.as_slice() {
+            [StreamMessage::Commit(tx)] => {
+                assert_eq!(tx.xid, Some(735));
+                assert_eq!(tx.lsn, 0x16B3800);
+                assert!(tx.commit_ts.is_some());
+            }
+            other => panic!("expected a commit, got {:?}", other),
+        }
+        // The next transaction doesn't inherit its xid and timestamp
+        assert!(decoder.xid.is_none());
+        assert!(decoder.commit_ts.is_none());
+    }
+
+    #[test]
+    fn unknown_relation() {
+        let mut decoder = PgOutputDecoder::default();
+        let msg = change(INSERT_TAG, &[(TUPLE_NEW, &[Some(UUID)])]);
+        assert!(decoder.decode(3, &mut Cursor::new(msg.freeze())).is_err());
+    }
+
+    #[test]
+    fn tuple_size_mismatch() {
+        let mut decoder = decoder();
+        let msg = change(INSERT_TAG, &[(TUPLE_NEW, &[Some(UUID)])]);
+        assert!(decoder.decode(3, &mut Cursor::new(msg.freeze())).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// How far the changes have been processed, to only acknowledge to PostgreSQL
+/// the position up to which nothing can be lost anymore.
+#[derive(Default)]
+pub struct Progress {
+    /// Every change up to this position has been forwarded to the sinks
+    forwarded: AtomicU64,
+    /// Position up to which each of the durable sinks (those holding back the
+    /// acknowledgment) confirmed the changes.
+    confirmed: Vec<Arc<AtomicU64>>,
+}
+
+impl Progress {
+    pub fn new(confirmed: Vec<Arc<AtomicU64>>) -> Self {
+        Self {
+            forwarded: AtomicU64::new(0),
+            confirmed,
+        }
+    }
+
+    /// Every change up to the position has been forwarded
+    pub fn forwarded(&self, lsn: u64) {
+        self.forwarded.store(lsn, Ordering::Release);
+    }
+
+    /// Get the position which can be acknowledged
+    pub fn processed(&self) -> u64 {
+        self.confirmed
+            .iter()
+            .map(|lsn| lsn.load(Ordering::Acquire))
+            .fold(self.forwarded.load(Ordering::Acquire), u64::min)
+    }
+}
@@ -1,16 +1,21 @@
+use super::{decoder, event::StreamMessage, progress::Progress, CdcError, Decoder};
+
+use crate::{utils::config::OutputPlugin, CONFIG};
+
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
 use once_cell::sync::Lazy;
 use std::{
-    io::{Cursor, Read},
+    io::Cursor,
     pin::Pin,
+    sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::Sender;
 use tokio_postgres::{Client, CopyBothDuplex, SimpleQueryMessage, SimpleQueryRow};
 
-const TIME_SEC_CONVERSION: u64 = 946_684_800;
+pub const TIME_SEC_CONVERSION: u64 = 946_684_800;
 const XLOG_DATA_TAG: u8 = b'w';
 const PRIMARY_KEEPALIVE_TAG: u8 = b'k';
 
@@ -22,7 +27,23 @@ pub fn current_time() -> u64 {
     EPOCH.elapsed().unwrap().as_micros() as u64
 }
 
-/// Send a CREATE_REPLICATION_SLOT ... TEMPORARY LOGICAL to the server.
+/// Format the LSN the way PostgreSQL does (ex: 16/B374D848)
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// Parse a LSN formatted the way PostgreSQL does (ex: 16/B374D848)
+pub fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    let high = u32::from_str_radix(high, 16).ok()?;
+    let low = u32::from_str_radix(low, 16).ok()?;
+
+    Some(((high as u64) << 32) | low as u64)
+}
+
+/// Send a CREATE_REPLICATION_SLOT ... LOGICAL to the server.
+/// The slot is TEMPORARY (dropped when the connection closes) unless
+/// `temporary` is false, in which case it survives restarts.
 /// The response to the CREATE_REPLICATION is not documented but based
 /// on the code, it's an HashMap containing the following:
 ///
@@ -30,10 +51,16 @@ pub fn current_time() -> u64 {
 /// 2. "consistent_point": LSN at which we became consistent
 /// 3. "snapshot_name": exported snapshot's name
 /// 4. "output_plugin": name of the output plugin, as requested
-pub async fn replication_slot_create(client: &Client, slot_name: &str) -> String {
+pub async fn replication_slot_create(
+    client: &Client,
+    slot_name: &str,
+    temporary: bool,
+) -> Result<String, CdcError> {
     let slot_query = &format!(
-        "CREATE_REPLICATION_SLOT {} TEMPORARY LOGICAL wal2json NOEXPORT_SNAPSHOT",
-        slot_name
+        "CREATE_REPLICATION_SLOT {} {}LOGICAL {} NOEXPORT_SNAPSHOT",
+        slot_name,
+        if temporary { "TEMPORARY " } else { "" },
+        CONFIG.output_plugin.name()
     );
 
     let resp: Vec<SimpleQueryRow> = match client.simple_query(slot_query).await {
@@ -49,11 +76,19 @@ pub async fn replication_slot_create(client: &Client, slot_name: &str) -> String
                 "Replication: '{}' cannot get the consistent_point: {}",
                 slot_name, e
             );
-            std::process::exit(1);
+            return Err(e.into());
         }
     };
 
-    let lsn = resp[0].get("consistent_point").unwrap().to_owned();
+    let lsn = match resp.first().and_then(|row| row.get("consistent_point")) {
+        Some(lsn) => lsn.to_owned(),
+        None => {
+            return Err(CdcError::Config(format!(
+                "the creation of the slot '{}' did not return a consistent_point",
+                slot_name
+            )))
+        }
+    };
 
     trace!(
         "Replication: slot {} created and got lsn {}",
@@ -61,7 +96,86 @@ pub async fn replication_slot_create(client: &Client, slot_name: &str) -> String
         lsn
     );
 
-    lsn
+    Ok(lsn)
+}
+
+/// Look for an existing (permanent) slot named slot_name and return
+/// the LSN up to which the server knows we've consumed the changes.
+///
+/// Return None if the slot does not exist yet.
+pub async fn replication_slot_lookup(
+    client: &Client,
+    slot_name: &str,
+) -> Result<Option<String>, CdcError> {
+    let lookup_query = &format!(
+        "SELECT plugin, confirmed_flush_lsn, restart_lsn FROM pg_replication_slots WHERE slot_name = '{}';",
+        slot_name
+    );
+
+    let row = match client.simple_query(lookup_query).await {
+        Ok(result) => result.into_iter().find_map(|data| match data {
+            SimpleQueryMessage::Row(row) => Some(row),
+            _ => None,
+        }),
+        Err(e) => {
+            error!("Replication: cannot lookup the slot '{}': {}", slot_name, e);
+            return Err(e.into());
+        }
+    };
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    if row.get("plugin") != Some(CONFIG.output_plugin.name()) {
+        return Err(CdcError::Config(format!(
+            "slot '{}' exists but uses the plugin {:?} instead of {}",
+            slot_name,
+            row.get("plugin"),
+            CONFIG.output_plugin.name()
+        )));
+    }
+
+    // confirmed_flush_lsn should always be set for a logical slot, but fallback
+    // to the restart_lsn just in case (both are valid starting point).
+    let lsn = row
+        .get("confirmed_flush_lsn")
+        .or_else(|| row.get("restart_lsn"))
+        .unwrap_or("0/0")
+        .to_owned();
+
+    trace!(
+        "Replication: slot {} already exists, resuming from lsn {}",
+        slot_name,
+        lsn
+    );
+
+    Ok(Some(lsn))
+}
+
+/// Reuse the permanent slot named slot_name if it exists, or create it otherwise.
+///
+/// Return the LSN from which the replication should be started.
+pub async fn replication_slot_get_or_create(
+    client: &Client,
+    slot_name: &str,
+) -> Result<String, CdcError> {
+    // Slot names can only contain lower case letters, numbers, and the underscore character.
+    if slot_name.is_empty()
+        || !slot_name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(CdcError::Config(format!(
+            "'{}' is not a valid slot name (only [a-z0-9_] allowed)",
+            slot_name
+        )));
+    }
+
+    match replication_slot_lookup(client, slot_name).await? {
+        Some(lsn) => Ok(lsn),
+        None => replication_slot_create(client, slot_name, false).await,
+    }
 }
 
 /// Starts streaming logical changes from replication slot pgcdc_repl,
@@ -70,14 +184,19 @@ pub async fn replication_stream_start(
     client: &Client,
     slot_name: &str,
     start_lsn: &str,
-) -> CopyBothDuplex<Bytes> {
-    let repl_query = format!("START_REPLICATION SLOT {} LOGICAL {}", slot_name, start_lsn);
+) -> Result<CopyBothDuplex<Bytes>, CdcError> {
+    let repl_query = format!(
+        "START_REPLICATION SLOT {} LOGICAL {}{}",
+        slot_name,
+        start_lsn,
+        plugin_options()?
+    );
     let copy_both_result = client.copy_both_simple::<bytes::Bytes>(&repl_query).await;
     let duplex_stream = match copy_both_result {
         Ok(result) => result,
         Err(e) => {
             error!("Replication: cannot get the a CopyBothDuplex: {}", e);
-            std::process::exit(1);
+            return Err(e.into());
         }
     };
 
@@ -87,23 +206,66 @@ pub async fn replication_stream_start(
         start_lsn
     );
 
-    duplex_stream
+    Ok(duplex_stream)
+}
+
+/// Build the options list passed to the output plugin by START_REPLICATION
+fn plugin_options() -> Result<String, CdcError> {
+    match CONFIG.output_plugin {
+        OutputPlugin::Wal2json => match CONFIG.wal2json_format_version {
+            1 => Ok(String::from(
+                " (\"include-timestamp\" '1', \"include-xids\" '1', \"include-lsn\" '1')",
+            )),
+            2 => Ok(String::from(
+                " (\"format-version\" '2', \"include-timestamp\" '1', \"include-xids\" '1', \"include-lsn\" '1')",
+            )),
+            version => Err(CdcError::Config(format!(
+                "wal2json format-version {} is not supported (1 or 2)",
+                version
+            ))),
+        },
+        OutputPlugin::Pgoutput => {
+            let publication = match &CONFIG.publication_name {
+                Some(publication) => publication.replace('\'', "''"),
+                None => {
+                    return Err(CdcError::Config(String::from(
+                        "pgoutput requires the publication_name to be set",
+                    )))
+                }
+            };
+            Ok(format!(
+                " (proto_version '1', publication_names '{}')",
+                publication
+            ))
+        }
+    }
 }
 
 /// Tries to read and process one message from a replication stream, using async I/O.
-pub async fn replication_stream_poll(duplex_stream: CopyBothDuplex<Bytes>, tx: Sender<String>) {
+///
+/// Only the position processed by the forwarder is reported as flushed to PostgreSQL,
+/// so that the changes still in flight are streamed again after a restart.
+pub async fn replication_stream_poll(
+    duplex_stream: CopyBothDuplex<Bytes>,
+    tx: Sender<StreamMessage>,
+    progress: Arc<Progress>,
+) {
     let mut boxed = Box::pin(duplex_stream);
     // PostgreSQL will default timeout at 1min so 10s is pretty much "ok".
     // Even in case where there's a lot of messages to handle, the tokio::select should
     // take the interval.tick() at least once :)
     let mut interval = tokio::time::interval(Duration::from_secs(10));
     let mut sync_lsn: u64 = 0;
+    // Decoder of the output plugin, it's kept for the whole stream as it can be
+    // stateful (pgoutput's relations definitions for example).
+    let mut decoder = decoder();
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
                 trace!("Replication: sending the keepalive to check the state of the connection");
-                match send_checkpoint(&mut boxed, sync_lsn).await {
+                let flushed_lsn = progress.processed();
+                match send_checkpoint(&mut boxed, sync_lsn, flushed_lsn).await {
                     Ok(_) => {},
                     Err(e) => {
                         error!("Replication: cannot contact the database: {}", e);
@@ -125,14 +287,16 @@ pub async fn replication_stream_poll(duplex_stream: CopyBothDuplex<Bytes>, tx: S
 
                         match tag {
                             XLOG_DATA_TAG => {
-                                parse_xlogdata_message(&mut buf, &mut sync_lsn, &tx).await;
+                                if !parse_xlogdata_message(&mut buf, &mut sync_lsn, decoder.as_mut(), &tx).await {
+                                    return;
+                                }
                             }
                             // The keepalive here is not mandatory as we already send a keepalive every 10s
                             // but for the sake of stableness, I keep it here.
                             PRIMARY_KEEPALIVE_TAG => {
                                 // NOTE: Disabled because when the database is restarting -> will spam with reply
                                 //       because it seems that PostgreSQL will send the request over and over again.
-                                // match parse_keepalive_message(&mut boxed, &mut buf, &mut sync_lsn).await {
+                                // match parse_keepalive_message(&mut boxed, &mut buf, &mut sync_lsn, &progress).await {
                                 //     Ok(_) => {},
                                 //     Err(e) => {
                                 //         error!("Replication: parse_keepalive_message failed: {}", e);
@@ -164,14 +328,21 @@ pub async fn replication_stream_poll(duplex_stream: CopyBothDuplex<Bytes>, tx: S
 /// - u64: The starting point of the WAL data in this message.
 /// - u64: The current end of WAL on the server.
 /// - u64: The server's system clock at the time of transmission, as microseconds
-///        since midnight on 2000-01-01.
+///   since midnight on 2000-01-01.
 /// - Byte(n): The output from the logical replication output plugin.
-async fn parse_xlogdata_message(buf: &mut Cursor<Bytes>, sync_lsn: &mut u64, tx: &Sender<String>) {
+///
+/// Return false if the changes cannot be sent to the forwarder anymore.
+async fn parse_xlogdata_message(
+    buf: &mut Cursor<Bytes>,
+    sync_lsn: &mut u64,
+    decoder: &mut dyn Decoder,
+    tx: &Sender<StreamMessage>,
+) -> bool {
     let wal_pos = match buf.read_u64::<BigEndian>() {
         Ok(wal) => wal,
         Err(e) => {
             error!("XLogData: cannot read_u64 wal_pos: {}", e);
-            return;
+            return true;
         }
     };
     let _wal_end = buf.read_u64::<BigEndian>();
@@ -179,28 +350,32 @@ async fn parse_xlogdata_message(buf: &mut Cursor<Bytes>, sync_lsn: &mut u64, tx:
 
     // trace!("XLogData: wal_pos {}/{:X}", wal_pos >> 32, wal_pos);
 
-    let mut data: String = String::with_capacity(32);
-    match buf.read_to_string(&mut data) {
-        Ok(size) => {
-            if size == 0 {
-                return;
-            }
-        }
+    // Decode the plugin's output into our own ChangeEvents (and commits)
+    let messages = match decoder.decode(wal_pos, buf) {
+        Ok(messages) => messages,
         Err(e) => {
-            error!("XLogData: cannot read_to_string: {}", e);
-            return;
+            error!("XLogData: cannot decode the message: {}", e);
+            return true;
         }
     };
-    // Broadcast data to the transmitter
+
+    // Broadcast data to the transmitter, followed by the position to acknowledge
+    // once they've been forwarded (even if there was no change in this message).
     // send can fail if the other half of the channel is closed, either due to close
     // or because the Receiver has been dropped. In addition send will also block until
     // there is a room for the message into the queue.
-    if let Err(e) = tx.send(data).await {
-        error!("XLogData: can't send to the channel due to: {}", e);
-        std::process::exit(1);
+    let messages = messages
+        .into_iter()
+        .chain(std::iter::once(StreamMessage::Checkpoint(wal_pos)));
+    for message in messages {
+        if let Err(e) = tx.send(message).await {
+            error!("XLogData: can't send to the channel due to: {}", e);
+            return false;
+        }
     }
 
     *sync_lsn = wal_pos;
+    true
 }
 
 /// Parses a "Primary keepalive message" received from the server. It is packed binary
@@ -208,13 +383,14 @@ async fn parse_xlogdata_message(buf: &mut Cursor<Bytes>, sync_lsn: &mut u64, tx:
 ///
 /// - u64: The current end of WAL on the server.
 /// - u64: The server's system clock at the time of transmission, as microseconds
-///        since midnight on 2000-01-01.
+///   since midnight on 2000-01-01.
 /// - u8: 1 means that the client should reply to this message as soon as possible,
-///       to avoid a timeout disconnect. 0 otherwise.
+///   to avoid a timeout disconnect. 0 otherwise.
 async fn _parse_keepalive_message(
     conn: &mut Pin<Box<CopyBothDuplex<Bytes>>>,
     buf: &mut Cursor<Bytes>,
     sync_lsn: &mut u64,
+    progress: &Progress,
 ) -> Result<(), tokio_postgres::Error> {
     let wal_pos = buf.read_u64::<BigEndian>().unwrap();
     let _ = buf.read_i64::<BigEndian>(); // timestamp
@@ -246,14 +422,14 @@ async fn _parse_keepalive_message(
         // if we can't send the checkpoint, PostgreSQL
         // will cut the connection anyway and we'll just
         // restart it.
-        return send_checkpoint(conn, *sync_lsn).await;
+        return send_checkpoint(conn, *sync_lsn, progress.processed()).await;
     }
 
     Ok(())
 }
 
 /// Send a "Standby status update" message to server, indicating the LSN up to which we
-/// have received logs. This message is packed binary with the following structure:
+/// have received logs (written) and forwarded them (flushed). This message is packed binary with the following structure:
 ///
 /// - u8('r'): Identifies the message as a receiver status update.
 /// - u64: The location of the last WAL byte + 1 received by the client.
@@ -263,20 +439,27 @@ async fn _parse_keepalive_message(
 /// - u8: If 1, the client requests the server to reply to this message immediately.
 async fn send_checkpoint(
     conn: &mut Pin<Box<CopyBothDuplex<Bytes>>>,
-    lsn: u64,
+    written_lsn: u64,
+    flushed_lsn: u64,
 ) -> Result<(), tokio_postgres::Error> {
     let mut ka_buf = BytesMut::with_capacity(34);
 
     ka_buf.put_u8(b'r');
-    ka_buf.put_u64(lsn);
-    ka_buf.put_u64(lsn);
+    ka_buf.put_u64(written_lsn);
+    ka_buf.put_u64(flushed_lsn);
     ka_buf.put_u64(0); // Only used by physical replication
     ka_buf.put_u64(current_time());
     ka_buf.put_u8(0);
 
     let res = (*conn).send(ka_buf.freeze()).await;
 
-    trace!("Checkpoint: lsn: {}/{:X}", lsn >> 32, lsn);
+    trace!(
+        "Checkpoint: written: {}/{:X}, flushed: {}/{:X}",
+        written_lsn >> 32,
+        written_lsn,
+        flushed_lsn >> 32,
+        flushed_lsn
+    );
 
     res
 }
@@ -0,0 +1,391 @@
+//! Decoder for the wal2json output plugin, in both format-version 1
+//! (one message per transaction) and format-version 2 (one message per change).
+//!
+//! See https://github.com/eulerto/wal2json
+
+use super::{
+    event::{ChangeEvent, ChangeKind, Column, Position, StreamMessage, Transaction},
+    replication::parse_lsn,
+    resolve_table_name, Decoder,
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{self, Cursor, Read};
+
+/// Format of the timestamps written by wal2json (ex: 2019-12-29 04:58:34.806671+00)
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f%#z";
+
+/// format-version 1: a whole transaction
+#[derive(Deserialize)]
+struct TransactionV1 {
+    xid: Option<u32>,
+    /// End of the commit (include-lsn)
+    nextlsn: Option<String>,
+    timestamp: Option<String>,
+    change: Vec<ChangeV1>,
+}
+
+/// format-version 1: a change inside of a transaction
+#[derive(Deserialize)]
+struct ChangeV1 {
+    kind: String,
+    #[serde(default)]
+    schema: String,
+    #[serde(default)]
+    table: String,
+    #[serde(default)]
+    columnnames: Vec<String>,
+    #[serde(default)]
+    columntypes: Vec<String>,
+    #[serde(default)]
+    columnvalues: Vec<Value>,
+    oldkeys: Option<OldKeysV1>,
+}
+
+/// format-version 1: old keys of an update/delete
+#[derive(Deserialize)]
+struct OldKeysV1 {
+    keynames: Vec<String>,
+    #[serde(default)]
+    keytypes: Vec<String>,
+    keyvalues: Vec<Value>,
+}
+
+/// format-version 2: a single change, begin or commit record
+#[derive(Deserialize)]
+struct ChangeV2 {
+    action: String,
+    #[serde(default)]
+    schema: String,
+    #[serde(default)]
+    table: String,
+    xid: Option<u32>,
+    /// End of the commit, for the begin record (include-lsn)
+    nextlsn: Option<String>,
+    timestamp: Option<String>,
+    #[serde(default)]
+    columns: Vec<Column>,
+    #[serde(default)]
+    identity: Vec<Column>,
+}
+
+/// Stateful (for the format-version 2 transaction's xid, timestamp and position) wal2json decoder
+pub struct Wal2JsonDecoder {
+    format_version: u8,
+    xid: Option<u32>,
+    commit_ts: Option<DateTime<Utc>>,
+    /// Position of the next change of the transaction
+    position: Position,
+}
+
+impl Wal2JsonDecoder {
+    pub fn new(format_version: u8) -> Self {
+        Self {
+            format_version,
+            xid: None,
+            commit_ts: None,
+            position: Position::default(),
+        }
+    }
+
+    fn decode_v1(&mut self, lsn: u64, mut data: String) -> io::Result<Vec<StreamMessage>> {
+        let tx: TransactionV1 = unsafe { simd_json::from_str(&mut data) }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let commit_ts = tx.timestamp.as_deref().and_then(parse_timestamp);
+        let commit_lsn = commit_lsn(tx.nextlsn.as_deref(), lsn);
+
+        let xid = tx.xid;
+        let changes = tx
+            .change
+            .into_iter()
+            .filter_map(|change| {
+                let kind = match parse_kind(&change.kind) {
+                    Some(kind) => kind,
+                    None => {
+                        trace!("Wal2json: skipping change of kind {}", change.kind);
+                        return None;
+                    }
+                };
+
+                Some(ChangeEvent {
+                    table: resolve_table_name(&change.table),
+                    schema: change.schema,
+                    kind,
+                    columns: zip_columns(
+                        change.columnnames,
+                        change.columntypes,
+                        change.columnvalues,
+                    ),
+                    old_keys: change.oldkeys.map_or_else(Vec::new, |keys| {
+                        zip_columns(keys.keynames, keys.keytypes, keys.keyvalues)
+                    }),
+                    lsn,
+                    position: Position::default(),
+                    commit_ts,
+                    xid,
+                })
+            })
+            // The changes skipped don't have a position
+            .zip(0..)
+            .map(|(mut event, index)| {
+                event.position = Position::new(commit_lsn, index);
+                StreamMessage::Change(event)
+            });
+
+        // The whole transaction is in the message
+        Ok(changes
+            .chain(std::iter::once(StreamMessage::Commit(Transaction {
+                xid,
+                commit_ts,
+                lsn,
+            })))
+            .collect())
+    }
+
+    fn decode_v2(&mut self, lsn: u64, mut data: String) -> io::Result<Vec<StreamMessage>> {
+        let change: ChangeV2 = unsafe { simd_json::from_str(&mut data) }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let kind = match change.action.as_str() {
+            "I" => ChangeKind::Insert,
+            "U" => ChangeKind::Update,
+            "D" => ChangeKind::Delete,
+            // Keep the xid and timestamp of the transaction for its changes
+            "B" => {
+                self.xid = change.xid;
+                self.commit_ts = change.timestamp.as_deref().and_then(parse_timestamp);
+                self.position = Position::new(commit_lsn(change.nextlsn.as_deref(), lsn), 0);
+                return Ok(Vec::new());
+            }
+            "C" => {
+                return Ok(vec![StreamMessage::Commit(Transaction {
+                    xid: change.xid.or(self.xid.take()),
+                    commit_ts: self.commit_ts.take(),
+                    lsn,
+                })]);
+            }
+            // Truncate and Message are not forwarded
+            action => {
+                trace!("Wal2json: skipping action {}", action);
+                return Ok(Vec::new());
+            }
+        };
+
+        let position = self.position;
+        self.position.index += 1;
+
+        Ok(vec![StreamMessage::Change(ChangeEvent {
+            table: resolve_table_name(&change.table),
+            schema: change.schema,
+            kind,
+            columns: change.columns,
+            old_keys: change.identity,
+            lsn,
+            position,
+            commit_ts: change
+                .timestamp
+                .as_deref()
+                .and_then(parse_timestamp)
+                .or(self.commit_ts),
+            xid: change.xid.or(self.xid),
+        })])
+    }
+}
+
+impl Decoder for Wal2JsonDecoder {
+    fn decode(&mut self, lsn: u64, buf: &mut Cursor<Bytes>) -> io::Result<Vec<StreamMessage>> {
+        let mut data: String = String::with_capacity(32);
+        if buf.read_to_string(&mut data)? == 0 {
+            return Ok(Vec::new());
+        }
+
+        match self.format_version {
+            2 => self.decode_v2(lsn, data),
+            _ => self.decode_v1(lsn, data),
+        }
+    }
+}
+
+fn parse_kind(kind: &str) -> Option<ChangeKind> {
+    match kind {
+        "insert" => Some(ChangeKind::Insert),
+        "update" => Some(ChangeKind::Update),
+        "delete" => Some(ChangeKind::Delete),
+        _ => None,
+    }
+}
+
+fn parse_timestamp(ts: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_str(ts, TIMESTAMP_FORMAT) {
+        Ok(ts) => Some(ts.with_timezone(&Utc)),
+        Err(e) => {
+            error!("Wal2json: cannot parse the timestamp {}: {}", ts, e);
+            None
+        }
+    }
+}
+
+/// LSN of the commit of the transaction from its nextlsn (asked using include-lsn),
+/// or the LSN of its first message if missing.
+fn commit_lsn(nextlsn: Option<&str>, lsn: u64) -> u64 {
+    match nextlsn.map(|nextlsn| (nextlsn, parse_lsn(nextlsn))) {
+        Some((_, Some(commit_lsn))) => commit_lsn,
+        Some((nextlsn, None)) => {
+            error!("Wal2json: cannot parse the nextlsn {}", nextlsn);
+            lsn
+        }
+        None => lsn,
+    }
+}
+
+/// Build the columns out of the format-version 1 separated arrays
+fn zip_columns(names: Vec<String>, types: Vec<String>, values: Vec<Value>) -> Vec<Column> {
+    let mut types = types.into_iter();
+    names
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| Column {
+            name,
+            type_name: types.next().unwrap_or_default(),
+            value,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(decoder: &mut Wal2JsonDecoder, lsn: u64, data: &str) -> Vec<StreamMessage> {
+        decoder
+            .decode(lsn, &mut Cursor::new(Bytes::from(data.to_owned())))
+            .unwrap()
+    }
+
+    fn changes(messages: &[StreamMessage]) -> Vec<&ChangeEvent> {
+        messages
+            .iter()
+            .filter_map(|message| match message {
+                StreamMessage::Change(event) => Some(event),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const TX_V1: &str = r#"{"xid":735,"nextlsn":"0/16B3830","timestamp":"2022-01-01 10:00:00.123+00","change":[
+        {"kind":"insert","schema":"public","table":"hosts","columnnames":["uuid","cpus"],"columntypes":["uuid","integer"],"columnvalues":["X",8]},
+        {"kind":"update","schema":"public","table":"hosts","columnnames":["uuid","cpus"],"columntypes":["uuid","integer"],"columnvalues":["X",4],"oldkeys":{"keynames":["uuid"],"keytypes":["uuid"],"keyvalues":["X"]}},
+        {"kind":"delete","schema":"public","table":"hosts","oldkeys":{"keynames":["uuid"],"keytypes":["uuid"],"keyvalues":["X"]}},
+        {"kind":"message","prefix":"x","content":"y"}
+    ]}"#;
+
+    #[test]
+    fn v1_transaction() {
+        let mut decoder = Wal2JsonDecoder::new(1);
+        let messages = decode(&mut decoder, 42, TX_V1);
+
+        let events = changes(&messages);
+        let kinds: Vec<ChangeKind> = events.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![ChangeKind::Insert, ChangeKind::Update, ChangeKind::Delete]
+        );
+
+        let insert = events[0];
+        assert_eq!(insert.table, "hosts");
+        assert_eq!(insert.lsn, 42);
+        assert_eq!(insert.xid, Some(735));
+        assert_eq!(
+            insert.commit_ts.map(|ts| ts.to_rfc3339()).as_deref(),
+            Some("2022-01-01T10:00:00.123+00:00")
+        );
+        assert_eq!(insert.columns[1].name, "cpus");
+        assert_eq!(insert.columns[1].type_name, "integer");
+        assert_eq!(insert.columns[1].value, Value::from(8));
+        assert!(insert.old_keys.is_empty());
+
+        assert_eq!(events[1].old_keys[0].name, "uuid");
+        assert!(events[2].columns.is_empty());
+        assert_eq!(events[2].old_keys[0].value, Value::from("X"));
+
+        // The changes share the LSN of the transaction, but not their position
+        let positions: Vec<String> = events.iter().map(|e| e.position.to_string()).collect();
+        assert_eq!(positions, vec!["0/16B3830:0", "0/16B3830:1", "0/16B3830:2"]);
+
+        // The whole transaction is in the message, it ends with its commit
+        match messages.last() {
+            Some(StreamMessage::Commit(tx)) => {
+                assert_eq!(tx.xid, Some(735));
+                assert_eq!(tx.lsn, 42);
+            }
+            other => panic!("expected a commit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v2_changes() {
+        let mut decoder = Wal2JsonDecoder::new(2);
+        assert!(decode(
+            &mut decoder,
+            1,
+            r#"{"action":"B","xid":735,"nextlsn":"0/16B3830","timestamp":"2022-01-01 10:00:00+00"}"#
+        )
+        .is_empty());
+
+        let messages = decode(
+            &mut decoder,
+            2,
+            r#"{"action":"U","xid":735,"schema":"public","table":"hosts","columns":[{"name":"uuid","type":"uuid","value":"X"},{"name":"cpus","type":"integer","value":4}],"identity":[{"name":"uuid","type":"uuid","value":"X"}]}"#,
+        );
+        let event = changes(&messages)[0];
+        assert_eq!(event.kind, ChangeKind::Update);
+        assert_eq!(event.lsn, 2);
+        assert_eq!(event.position, Position::new(0x16B3830, 0));
+        assert_eq!(event.xid, Some(735));
+        // The timestamp of the transaction is kept for its changes
+        assert!(event.commit_ts.is_some());
+        assert_eq!(event.columns.len(), 2);
+        assert_eq!(event.old_keys[0].name, "uuid");
+
+        // Truncates are not forwarded
+        assert!(decode(
+            &mut decoder,
+            3,
+            r#"{"action":"T","xid":735,"schema":"public","table":"hosts"}"#
+        )
+        .is_empty());
+
+        match decode(&mut decoder, 4, r#"{"action":"C","xid":735}"#).as_slice() {
+            [StreamMessage::Commit(tx)] => {
+                assert_eq!(tx.xid, Some(735));
+                assert!(tx.commit_ts.is_some());
+                assert_eq!(tx.lsn, 4);
+            }
+            other => panic!("expected a commit, got {:?}", other),
+        }
+
+        // The next transaction doesn't inherit its timestamp
+        let messages = decode(
+            &mut decoder,
+            5,
+            r#"{"action":"D","schema":"public","table":"hosts","identity":[{"name":"uuid","type":"uuid","value":"X"}]}"#,
+        );
+        assert!(changes(&messages)[0].commit_ts.is_none());
+    }
+
+    #[test]
+    fn invalid_message() {
+        let mut decoder = Wal2JsonDecoder::new(1);
+        let data = Bytes::from_static(b"{\"change\":");
+        assert!(decoder.decode(1, &mut Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut decoder = Wal2JsonDecoder::new(2);
+        assert!(decode(&mut decoder, 1, "").is_empty());
+    }
+}
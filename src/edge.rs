@@ -0,0 +1,110 @@
+use crate::{
+    api::ws_utils::{ServerState, SystemMessage},
+    cdc::replication::format_lsn,
+    forwarder::{
+        bus::{self, BusMessage},
+        sink::Sink,
+        websocket::WebsocketSink,
+    },
+    utils::backoff::Backoff,
+    CONFIG,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use redis::{aio::PubSub, AsyncCommands, RedisResult};
+use std::sync::Arc;
+
+/// Fan out the changes published (to Redis) by the ingest instance to the clients
+/// of the ServerState, subscribing again (with an exponential backoff) when the
+/// connection is lost.
+///
+/// The clients stay connected and are told when the stream goes down and comes back.
+pub async fn start_edge(server_state: Arc<ServerState>) {
+    let client = bus::redis_client();
+    let sink = WebsocketSink::new(server_state);
+    let mut backoff = Backoff::default();
+    // Since when the stream is down (if it is)
+    let mut down: Option<DateTime<Utc>> = None;
+    // Position of the last change received
+    let mut last_lsn = 0;
+
+    loop {
+        match subscribe(&client).await {
+            Ok(mut pubsub) => {
+                backoff.reset();
+                if let Some(down_since) = down.take() {
+                    info!("Edge: the stream is back");
+                    let message = SystemMessage::StreamResumed {
+                        down_since,
+                        downtime_ms: (Utc::now() - down_since).num_milliseconds(),
+                        last_lsn: format_lsn(last_lsn),
+                        resume_lsn: format_lsn(last_lsn),
+                        // Nothing is kept for us while unsubscribed
+                        gap: true,
+                    };
+                    sink.system(&message).await;
+                }
+
+                let mut messages = pubsub.on_message();
+                while let Some(msg) = messages.next().await {
+                    let payload: String = match msg.get_payload() {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            error!("Edge: cannot read the message: {}", e);
+                            continue;
+                        }
+                    };
+
+                    match serde_json::from_str::<BusMessage>(&payload) {
+                        Ok(BusMessage::Change(event)) => {
+                            last_lsn = event.lsn;
+                            sink.send(&event).await;
+                        }
+                        Ok(BusMessage::Commit(tx)) => sink.commit(&tx).await,
+                        Ok(BusMessage::System(message)) => sink.system(&message).await,
+                        Ok(BusMessage::Schema(schema)) => schema.apply(),
+                        Err(e) => error!("Edge: cannot decode the message: {}", e),
+                    }
+                }
+                error!("Edge: the subscription exited");
+            }
+            Err(e) => {
+                error!("Edge: cannot subscribe: {}", e);
+            }
+        }
+
+        if down.is_none() {
+            let since = Utc::now();
+            let message = SystemMessage::StreamDown {
+                since,
+                last_lsn: format_lsn(last_lsn),
+            };
+            sink.system(&message).await;
+            down = Some(since);
+        }
+
+        let delay = backoff.next_delay();
+        info!("Edge: subscribing again in {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Subscribe to the channel and get the last schema published. The schema is
+/// only read once subscribed, not to miss one published in between.
+async fn subscribe(client: &redis::Client) -> RedisResult<PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(&CONFIG.redis_channel).await?;
+
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let schema: Option<String> = conn.get(bus::schema_key()).await?;
+    match schema.map(|schema| serde_json::from_str::<BusMessage>(&schema)) {
+        Some(Ok(BusMessage::Schema(schema))) => schema.apply(),
+        Some(_) => error!("Edge: cannot decode the schema"),
+        None => {
+            warn!("Edge: no schema published yet, the subscriptions will be refused until then")
+        }
+    }
+
+    Ok(pubsub)
+}
@@ -0,0 +1,166 @@
+use super::sink::Sink;
+
+use crate::{
+    api::ws_utils::SystemMessage,
+    cdc::{
+        event::{ChangeEvent, Transaction},
+        TableColumns,
+    },
+    utils::backoff::Backoff,
+    COLUMNS, CONFIG, TABLES,
+};
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Number of messages waiting to be published
+const QUEUE_SIZE: usize = 1024;
+
+/// What the ingest instance publish (to Redis) for the edge ones
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum BusMessage {
+    Change(Arc<ChangeEvent>),
+    Commit(Transaction),
+    System(SystemMessage),
+    /// The tables and columns, needed by the edges to check the subscriptions
+    Schema(Schema),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Schema {
+    pub tables: Vec<String>,
+    pub columns: HashMap<String, TableColumns>,
+}
+
+impl Schema {
+    /// Get the tables and columns currently detected
+    pub fn current() -> Self {
+        Self {
+            tables: TABLES.read().unwrap().clone(),
+            columns: COLUMNS.read().unwrap().clone(),
+        }
+    }
+
+    /// Replace the tables and columns by those of the schema
+    pub fn apply(self) {
+        trace!("Bus: Allowed tables are: {:?}", &self.tables);
+        *TABLES.write().unwrap() = self.tables;
+        *COLUMNS.write().unwrap() = self.columns;
+    }
+}
+
+/// Key holding the last Schema published, for the edges starting after it
+pub fn schema_key() -> String {
+    format!("{}:schema", CONFIG.redis_channel)
+}
+
+/// Get the url of Redis, exit if not set as the ingest and edge roles need it
+pub fn redis_client() -> redis::Client {
+    let url = match &CONFIG.redis_url {
+        Some(url) => url,
+        None => {
+            error!("The redis_url is needed by the ingest and edge roles");
+            std::process::exit(1);
+        }
+    };
+
+    match redis::Client::open(url.as_str()) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("The redis_url is not valid: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Publish the changes (and the state of the stream) to Redis, for the edge
+/// instances to fan them out to their clients.
+pub struct RedisSink {
+    tx: Sender<BusMessage>,
+}
+
+impl RedisSink {
+    // Not a Default, it spawns the worker publishing to Redis
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(bus_worker(redis_client(), rx));
+
+        Self { tx }
+    }
+
+    async fn publish(&self, message: BusMessage) {
+        // Wait for some room in the queue rather than dropping it
+        if self.tx.send(message).await.is_err() {
+            error!("Bus: the publisher has stopped, dropping the message");
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for RedisSink {
+    async fn send(&self, event: &Arc<ChangeEvent>) {
+        self.publish(BusMessage::Change(event.clone())).await;
+    }
+
+    async fn commit(&self, tx: &Transaction) {
+        self.publish(BusMessage::Commit(tx.clone())).await;
+    }
+
+    async fn system(&self, message: &SystemMessage) {
+        self.publish(BusMessage::System(message.clone())).await;
+    }
+
+    async fn reloaded(&self) {
+        self.publish(BusMessage::Schema(Schema::current())).await;
+    }
+}
+
+async fn bus_worker(client: redis::Client, mut rx: Receiver<BusMessage>) {
+    let mut backoff = Backoff::default();
+    // Reconnect by itself once connected
+    let mut conn = loop {
+        match ConnectionManager::new(client.clone()).await {
+            Ok(conn) => break conn,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                error!(
+                    "Bus: cannot connect to Redis: {}, retrying in {:?}",
+                    e, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    };
+
+    while let Some(message) = rx.recv().await {
+        // serde_json::to_string cannot fail on our own enum
+        let payload = serde_json::to_string(&message).unwrap();
+
+        // Don't lose the message if Redis is unreachable, wait for it to come back
+        backoff.reset();
+        loop {
+            let mut pipe = redis::pipe();
+            if let BusMessage::Schema(_) = message {
+                pipe.set(schema_key(), &payload).ignore();
+            }
+            pipe.publish(&CONFIG.redis_channel, &payload).ignore();
+
+            match pipe.query_async::<()>(&mut conn).await {
+                Ok(_) => break,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Bus: cannot publish to Redis: {}, retrying in {:?}",
+                        e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
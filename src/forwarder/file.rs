@@ -0,0 +1,334 @@
+use super::{
+    serializer,
+    sink::{Sink, SinkFilter, SinkItem, SinkQueue},
+};
+
+use crate::{
+    cdc::event::ChangeEvent,
+    utils::{backoff::Backoff, config::FileSinkConfig},
+};
+
+use async_trait::async_trait;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Delays (in ms) between the retries of a write
+const RETRY_DELAY_MIN: u64 = 500;
+const RETRY_DELAY_MAX: u64 = 30000;
+
+/// Append each change matching the query to a file, as one JSON per line (NDJSON)
+///
+/// Unless lossy, a position is only acknowledged once every change before it
+/// has been written (and flushed), the writes being retried until then. What a
+/// failed write wrote is truncated, the lines are never written partially or twice.
+pub struct FileSink {
+    filter: SinkFilter,
+    queue: SinkQueue,
+}
+
+impl FileSink {
+    pub fn new(config: FileSinkConfig) -> Self {
+        let file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                error!("File sink: cannot open {}: {}", config.path, e);
+                std::process::exit(1);
+            }
+        };
+
+        let (queue, rx) = SinkQueue::new(
+            format!("file {}", config.path),
+            config.queue_size,
+            config.lossy,
+        );
+        let filter = SinkFilter::new(&config.query);
+        let confirmed = queue.processed();
+        // Writing to the file is blocking, use its own thread
+        std::thread::spawn(move || file_worker(config, file, rx, confirmed));
+
+        Self { filter, queue }
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn send(&self, event: &Arc<ChangeEvent>) {
+        if self.filter.matches(event) {
+            self.queue.push(event).await;
+        }
+    }
+
+    async fn checkpoint(&self, lsn: u64) {
+        self.queue.checkpoint(lsn).await;
+    }
+
+    fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+        self.queue.confirmed()
+    }
+}
+
+/// The file the changes are appended to
+trait Output: Write {
+    /// Size of the file
+    fn len(&self) -> io::Result<u64>;
+
+    /// Truncate the file to the size
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+impl Output for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+fn file_worker<F: Output>(
+    config: FileSinkConfig,
+    mut file: F,
+    mut rx: Receiver<SinkItem>,
+    confirmed: Arc<AtomicU64>,
+) {
+    // Size of the file once the last lines have been written, what's after is partial
+    let mut written = match file.len() {
+        Ok(len) => len,
+        Err(e) => {
+            error!("File sink: cannot get the size of {}: {}", config.path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut lines = Vec::new();
+
+    while let Some(item) = rx.blocking_recv() {
+        // Write every changes already queued at once
+        let mut checkpoint = None;
+        let mut next = Some(item);
+        while let Some(item) = next {
+            match item {
+                SinkItem::Change(event) => {
+                    let value = serializer::serialize(&event, config.format, None);
+                    // serde_json::to_writer cannot fail on a Value (nor a Vec)
+                    serde_json::to_writer(&mut lines, &value).unwrap();
+                    lines.push(b'\n');
+                }
+                SinkItem::Checkpoint(lsn) => checkpoint = Some(lsn),
+            }
+            next = rx.try_recv().ok();
+        }
+
+        let mut failed = false;
+        let done = retry(&config, || {
+            // Remove what the failed attempt wrote instead of appending the lines again
+            if failed {
+                file.set_len(written)?;
+            }
+            failed = true;
+            file.write_all(&lines)?;
+            file.flush()
+        });
+        match done {
+            true => written += lines.len() as u64,
+            // Lossy, the lines are dropped (along with what was written of them)
+            false => {
+                if let Err(e) = file.set_len(written) {
+                    error!("File sink: cannot truncate {}: {}", config.path, e);
+                }
+            }
+        }
+        lines.clear();
+
+        // Every change before has been written
+        if let Some(lsn) = checkpoint {
+            confirmed.store(lsn, Ordering::Release);
+        }
+    }
+}
+
+/// Run the operation on the file until it succeeds, only once if lossy.
+///
+/// Return false if it failed (lossy).
+fn retry<F>(config: &FileSinkConfig, mut operation: F) -> bool
+where
+    F: FnMut() -> io::Result<()>,
+{
+    let mut backoff = Backoff::new(RETRY_DELAY_MIN, RETRY_DELAY_MAX, 0.2);
+    loop {
+        match operation() {
+            Ok(()) => return true,
+            Err(e) if config.lossy => {
+                error!("File sink: cannot write to {}: {}", config.path, e);
+                return false;
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                error!(
+                    "File sink: cannot write to {}: {}, retrying in {:?}",
+                    config.path, e, delay
+                );
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::ws_utils::MessageFormat,
+        cdc::event::{ChangeKind, Column, Position},
+    };
+
+    use serde_json::{json, Value};
+
+    fn event(index: u32, uuid: &str) -> Arc<ChangeEvent> {
+        Arc::new(ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Insert,
+            columns: vec![Column {
+                name: String::from("uuid"),
+                type_name: String::from("text"),
+                value: Value::from(uuid),
+            }],
+            old_keys: Vec::new(),
+            lsn: 0x16B3748 + index as u64,
+            position: Position::new(0x16B3800, index),
+            commit_ts: None,
+            xid: Some(735),
+        })
+    }
+
+    /// A file whose first writes fail, after writing half of what they were given
+    struct Flaky {
+        file: File,
+        failures: usize,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                self.file.write_all(&buf[..buf.len() / 2])?;
+                return Err(io::Error::other("no space left on device"));
+            }
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Output for Flaky {
+        fn len(&self) -> io::Result<u64> {
+            self.file.len()
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            self.file.set_len(size)
+        }
+    }
+
+    /// A file in the temp dir, already containing a line
+    fn output(name: &str) -> (String, File) {
+        let path =
+            std::env::temp_dir().join(format!("pgcdc-{}-{}.ndjson", name, std::process::id()));
+        std::fs::write(&path, "{}\n").unwrap();
+        let file = OpenOptions::new().append(true).open(&path).unwrap();
+        (path.to_string_lossy().into_owned(), file)
+    }
+
+    /// Write the changes (and a checkpoint) to the output, return the position confirmed
+    /// and the lines of the file.
+    async fn write<F: Output + Send + 'static>(
+        path: String,
+        output: F,
+        lossy: bool,
+        events: &[Arc<ChangeEvent>],
+    ) -> (u64, Vec<Value>) {
+        let config = FileSinkConfig {
+            query: String::from("*:hosts"),
+            format: MessageFormat::Envelope,
+            path: path.clone(),
+            queue_size: 16,
+            lossy,
+        };
+
+        let (queue, rx) = SinkQueue::new(String::from("file"), 16, lossy);
+        let confirmed = queue.processed();
+        for event in events {
+            queue.push(event).await;
+        }
+        queue.checkpoint(0x16B3900).await;
+        drop(queue);
+
+        let worker_confirmed = confirmed.clone();
+        tokio::task::spawn_blocking(move || file_worker(config, output, rx, worker_confirmed))
+            .await
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(content.ends_with('\n'));
+        let lines = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (confirmed.load(Ordering::Acquire), lines)
+    }
+
+    #[tokio::test]
+    async fn ndjson() {
+        let (path, file) = output("ndjson");
+        // A value with a line break is still written on a single line
+        let events = [event(0, "first\nline"), event(1, "second")];
+        let (confirmed, lines) = write(path, file, false, &events).await;
+        assert_eq!(confirmed, 0x16B3900);
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["table"], "hosts");
+        assert_eq!(lines[1]["new"], json!({"uuid": "first\nline"}));
+        assert_eq!(lines[2]["new"], json!({"uuid": "second"}));
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_truncated() {
+        let (path, file) = output("failed");
+        let flaky = Flaky { file, failures: 1 };
+        let events = [event(0, "first"), event(1, "second")];
+        let (confirmed, lines) = write(path, flaky, false, &events).await;
+        assert_eq!(confirmed, 0x16B3900);
+
+        // Written once retried, without what the failed write wrote
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], json!({}));
+        assert_eq!(lines[1]["new"], json!({"uuid": "first"}));
+        assert_eq!(lines[2]["new"], json!({"uuid": "second"}));
+    }
+
+    #[tokio::test]
+    async fn lossy_failed_write_is_truncated() {
+        let (path, file) = output("lossy");
+        let flaky = Flaky { file, failures: 1 };
+        let (_, lines) = write(path, flaky, true, &[event(0, "first")]).await;
+
+        // Dropped, along with what the failed write wrote
+        assert_eq!(lines, vec![json!({})]);
+    }
+}
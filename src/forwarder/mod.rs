@@ -1,138 +1,50 @@
-use crate::api::ws_utils::{self, ServerState, DELETE, INSERT, UPDATE};
-#[cfg(feature = "timescale")]
-use crate::{cdc::extract_hyper_idx, TABLES_LOOKUP};
+use crate::cdc::{event::StreamMessage, progress::Progress};
 
-use axum::extract::ws::Message;
-use serde_json::Value;
-use std::{collections::HashSet, sync::Arc};
+use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
 
-/// Get the table name from an &str, returning a String
-/// This is used due to TimescaleDB renaming the hypertable using a
-/// pattern '_hyper_' with some number and all. If we can't convert the pattern
-/// back to it's original table name, return the pattern name.
-#[cfg(feature = "timescale")]
-fn get_table_name(table_name: &str) -> String {
-    if table_name.starts_with("_hyper_") {
-        let idx = match extract_hyper_idx(table_name) {
-            Ok(idx) => idx,
-            Err(_) => {
-                error!(
-                    "Match table: table {} cannot be deconstructed into an idx",
-                    table_name
-                );
-                return table_name.to_owned();
-            }
-        };
-        // Get the table name from the index and return an owned String
-        // or continue the loop and skip this value if not found.
-        match TABLES_LOOKUP.read().unwrap().get(&idx) {
-            Some(val) => return val.to_owned(),
-            None => {
-                error!(
-                    "Match table: table not found inside using index: {}:{}",
-                    idx, table_name,
-                );
-                return table_name.to_owned();
-            }
-        }
-    }
-    table_name.to_owned()
-}
+use sink::Sinks;
 
-/// Send a message to a specific group of sessions (insert, update or delete)
-fn send_message(
-    message: &serde_json::Value,
-    sessions: Option<&HashSet<usize>>,
-    server_state: &Arc<ServerState>,
-) {
-    // If no session were defined, skip
-    if sessions.is_none() {
-        return;
-    }
-    // For every sessions'id in the tables HashMap
-    for id in sessions.unwrap() {
-        // Get the client from the clients list inside the server_state
-        if let Some(client) = server_state.clients.read().unwrap().get(id) {
-            // Check if the client asked for a particular filter
-            let to_send = match &client.watch_for.specific {
-                Some(specific) => specific.match_filter(message),
-                None => true,
-            };
-
-            if to_send {
-                // Send the message to the client
-                if let Err(_disconnected) = client.gate.send(Ok(Message::Text(message.to_string())))
-                {
-                    error!("Send_message: client disconnected, should be removed soon");
-                }
-            }
-        }
-    }
-}
+#[cfg(feature = "redis")]
+pub mod bus;
+mod file;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "nats")]
+mod publisher;
+pub mod serializer;
+pub mod sink;
+mod webhook;
+pub mod websocket;
 
-/// Start a new task which loop over the Receiver's value it may get and forward them to websockets.
-pub async fn start_forwarder(mut rx: Receiver<String>, server_state: Arc<ServerState>) {
+/// Start a new task which loop over the Receiver's value it may get and forward them to the sinks.
+/// The progress is updated once every change up to a position has been forwarded.
+pub async fn start_forwarder(
+    mut rx: Receiver<StreamMessage>,
+    sinks: Sinks,
+    progress: Arc<Progress>,
+) {
     trace!("Forwarder: Started and waiting for a message");
 
     loop {
         match rx.recv().await {
-            Some(mut value) => {
-                // Convert the data to a Value enum of serde_json
-                // Using simd optimization through simd_json crate.
-                let data: Value = unsafe { simd_json::from_str(&mut value) }.unwrap();
-                // Extract what we really want and assert that it exists
-                let changes = match data["change"].as_array() {
-                    Some(val) => val,
-                    None => {
-                        error!("Forwarder: The message is invalid: {}", data);
-                        continue;
-                    }
-                };
-                // For each change inside of changes, we do the following treatment
-                for change in changes {
-                    // Check the table (to str (using a match for safety))
-                    if let (Some(table_name), Some(change_type)) =
-                        (change["table"].as_str(), change["kind"].as_str())
-                    {
-                        // Get the table name from the _hyper_x_x_chunk
-                        // See comment in the main.rs for more information.
-                        #[cfg(feature = "timescale")]
-                        let table_name = get_table_name(table_name);
-                        #[cfg(not(feature = "timescale"))]
-                        let table_name = table_name.to_owned();
-                        // Construct the change_flag
-                        let mut change_flag = 0u8;
-                        // At this stage, the change_flag can be only be one of INSERT, UPDATE, DELETE
-                        // but not multiple of them.
-                        ws_utils::apply_flag(&mut change_flag, change_type);
-                        // Only send the message to those interested in the change_type
-                        if has_bit!(change_flag, INSERT) {
-                            // First get the lock over the RwLock guard
-                            let lock = server_state.inserts.read().unwrap();
-                            // Then get the sessions out of it
-                            let sessions = lock.get(&table_name);
-                            // And finally send the message to each client inside that sessions AHashSet
-                            send_message(change, sessions, &server_state);
-                        } else if has_bit!(change_flag, UPDATE) {
-                            let lock = server_state.updates.read().unwrap();
-                            let sessions = lock.get(&table_name);
-                            send_message(change, sessions, &server_state);
-                        } else if has_bit!(change_flag, DELETE) {
-                            let lock = server_state.deletes.read().unwrap();
-                            let sessions = lock.get(&table_name);
-                            send_message(change, sessions, &server_state);
-                        } else {
-                            error!("Forwarder: change_flag {:?} not handled.", change_flag);
-                            continue;
-                        };
-                    } else {
-                        error!(
-                            "Forwarder: table ({:?}) or change_type ({:?}) not present.",
-                            change["table"], change["kind"]
-                        );
-                    }
+            Some(StreamMessage::Change(event)) => {
+                // Shared by the sinks, which may keep it (history, queues)
+                let event = Arc::new(event);
+                for sink in sinks.iter() {
+                    sink.send(&event).await;
+                }
+            }
+            Some(StreamMessage::Commit(tx)) => {
+                for sink in sinks.iter() {
+                    sink.commit(&tx).await;
+                }
+            }
+            Some(StreamMessage::Checkpoint(lsn)) => {
+                for sink in sinks.iter() {
+                    sink.checkpoint(lsn).await;
                 }
+                progress.forwarded(lsn);
             }
             None => {
                 trace!("Channel returned None");
@@ -141,3 +53,130 @@ pub async fn start_forwarder(mut rx: Receiver<String>, server_state: Arc<ServerS
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::{
+            gate::Gate,
+            subscriptions::Subscriber,
+            ws_utils::{Encoding, MessageFormat, WsWatchFor, INSERT},
+        },
+        cdc::event::{ChangeEvent, ChangeKind, Position},
+        utils::config::SlowConsumerPolicy,
+    };
+
+    use async_trait::async_trait;
+    use sink::{Sink, SinkItem, SinkQueue};
+    use std::{
+        sync::atomic::{AtomicBool, AtomicU64},
+        time::Duration,
+    };
+    use tokio::sync::mpsc;
+    use websocket::{send_message, Batches};
+
+    /// Send the changes to a websocket client, as the WebsocketSink
+    struct Client {
+        subscriber: Arc<Subscriber>,
+    }
+
+    #[async_trait]
+    impl Sink for Client {
+        async fn send(&self, event: &Arc<ChangeEvent>) {
+            send_message(
+                event,
+                std::slice::from_ref(&self.subscriber),
+                &mut Batches::new(),
+            );
+        }
+    }
+
+    /// A sink whose task never processes its queue, as a webhook never answering
+    struct Stuck {
+        queue: SinkQueue,
+        _rx: mpsc::Receiver<SinkItem>,
+    }
+
+    #[async_trait]
+    impl Sink for Stuck {
+        async fn send(&self, event: &Arc<ChangeEvent>) {
+            self.queue.push(event).await;
+        }
+
+        async fn checkpoint(&self, lsn: u64) {
+            self.queue.checkpoint(lsn).await;
+        }
+
+        fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+            self.queue.confirmed()
+        }
+    }
+
+    fn change(lsn: u64) -> StreamMessage {
+        StreamMessage::Change(ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Insert,
+            columns: Vec::new(),
+            old_keys: Vec::new(),
+            lsn,
+            position: Position::new(lsn, 0),
+            commit_ts: None,
+            xid: None,
+        })
+    }
+
+    /// Forward 10 changes to a websocket client and a stuck sink, return the
+    /// number of messages the client received and the position acknowledged.
+    async fn forward(lossy: bool) -> (usize, u64) {
+        let gate = Gate::with_limits(0, 64, SlowConsumerPolicy::DropOldest);
+        let client = Client {
+            subscriber: Arc::new(Subscriber {
+                client: 0,
+                gate: gate.clone(),
+                format: MessageFormat::Envelope,
+                encoding: Encoding::Json,
+                tx: false,
+                tagged: Arc::new(AtomicBool::new(false)),
+                watch_for: Arc::new(WsWatchFor {
+                    id: String::from("hosts"),
+                    change_table: String::from("hosts"),
+                    change_flag: INSERT,
+                    specific: None,
+                    select: None,
+                }),
+            }),
+        };
+        let (queue, rx) = SinkQueue::new(String::from("stuck"), 2, lossy);
+        let stuck = Stuck { queue, _rx: rx };
+        let progress = Arc::new(Progress::new(stuck.confirmed().into_iter().collect()));
+        let sinks: Sinks = Arc::new(vec![Box::new(client), Box::new(stuck)]);
+
+        let (tx, rx) = mpsc::channel(64);
+        for lsn in 1..=10 {
+            tx.send(change(lsn)).await.unwrap();
+            tx.send(StreamMessage::Checkpoint(lsn)).await.unwrap();
+        }
+        drop(tx);
+        let forwarder = start_forwarder(rx, sinks, progress.clone());
+        let _ = tokio::time::timeout(Duration::from_secs(60), forwarder).await;
+
+        (gate.queued(), progress.processed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_sink_does_not_stall_the_websockets() {
+        // The changes the stuck sink cannot take are dropped
+        assert_eq!(forward(true).await, (10, 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_durable_sink_stalls_the_websockets() {
+        // Once its queue is full, the forwarder waits for the sink
+        let (received, processed) = forward(false).await;
+        assert!(received < 10);
+        assert_eq!(processed, 0);
+    }
+}
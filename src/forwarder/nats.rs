@@ -0,0 +1,53 @@
+use super::publisher::{Broker, BrokerMessage, Confirmation};
+
+use crate::cdc::replication::format_lsn;
+
+use async_nats::{jetstream, ConnectOptions, HeaderMap};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Header carrying the partition key of the change
+pub const KEY_HEADER: &str = "Pgcdc-Key";
+/// Header carrying the LSN of the change
+pub const LSN_HEADER: &str = "Pgcdc-Lsn";
+
+/// NATS JetStream, the subjects must be part of a stream for the changes to be confirmed
+pub struct NatsBroker {
+    jetstream: jetstream::Context,
+}
+
+impl NatsBroker {
+    pub async fn connect(url: String) -> Result<Arc<dyn Broker>, String> {
+        // Keep trying in the background if the server is not reachable yet
+        let client = ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(url)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Arc::new(Self {
+            jetstream: jetstream::new(client),
+        }))
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, message: &BrokerMessage) -> Result<Confirmation, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(LSN_HEADER, format_lsn(message.lsn).as_str());
+        if let Some(key) = &message.key {
+            headers.insert(KEY_HEADER, key.as_str());
+        }
+
+        let ack = self
+            .jetstream
+            .publish_with_headers(message.subject.clone(), headers, message.payload.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Box::pin(async move {
+            ack.await.map(|_| ()).map_err(|e| e.to_string())
+        }))
+    }
+}
@@ -0,0 +1,492 @@
+use super::{
+    serializer,
+    sink::{Sink, SinkFilter},
+};
+
+use crate::{
+    cdc::{event::ChangeEvent, replication::format_lsn},
+    utils::{backoff::Backoff, config::PublisherSinkConfig},
+    COLUMNS,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, OptionFuture};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Delays (in ms) between the retries of a publication
+const RETRY_DELAY_MIN: u64 = 500;
+const RETRY_DELAY_MAX: u64 = 30000;
+
+/// Number of changes (and checkpoints) waiting to be published
+const QUEUE_SIZE: usize = 1024;
+
+/// A change published to the broker
+#[derive(Clone)]
+pub struct BrokerMessage {
+    /// Subject (or topic) derived from the name of the table
+    pub subject: String,
+    /// Partition key of the change
+    pub key: Option<String>,
+    pub lsn: u64,
+    pub payload: Bytes,
+}
+
+/// Resolve once the broker confirmed the message (stored it durably)
+pub type Confirmation = BoxFuture<'static, Result<(), String>>;
+
+/// Message broker the changes are published to (NATS JetStream, ...)
+#[async_trait]
+pub trait Broker: Send + Sync {
+    /// Send the message, without waiting for the broker to confirm it
+    async fn publish(&self, message: &BrokerMessage) -> Result<Confirmation, String>;
+}
+
+enum Item {
+    Change(Arc<ChangeEvent>),
+    Checkpoint(u64),
+}
+
+/// Publish each change matching the query to a broker. A position is only
+/// confirmed (and so acknowledged to PostgreSQL) once the broker confirmed
+/// every change before it, the changes are never dropped. When a change is not
+/// confirmed, it's published again along with every change after it, in order.
+pub struct PublisherSink {
+    filter: SinkFilter,
+    tx: Sender<Item>,
+    confirmed: Arc<AtomicU64>,
+}
+
+impl PublisherSink {
+    pub fn new(
+        config: PublisherSinkConfig,
+        broker: BoxFuture<'static, Result<Arc<dyn Broker>, String>>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let filter = SinkFilter::new(&config.query);
+        let confirmed = Arc::new(AtomicU64::new(0));
+        tokio::spawn(publisher_worker(config, broker, rx, confirmed.clone()));
+
+        Self {
+            filter,
+            tx,
+            confirmed,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for PublisherSink {
+    async fn send(&self, event: &Arc<ChangeEvent>) {
+        // Wait for some room in the queue rather than dropping it
+        if self.filter.matches(event) && self.tx.send(Item::Change(event.clone())).await.is_err() {
+            error!("Publisher: has stopped, the changes won't be acknowledged anymore");
+        }
+    }
+
+    async fn checkpoint(&self, lsn: u64) {
+        if self.tx.send(Item::Checkpoint(lsn)).await.is_err() {
+            error!("Publisher: has stopped, the changes won't be acknowledged anymore");
+        }
+    }
+
+    fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.confirmed.clone())
+    }
+}
+
+/// A change published and its confirmation, or a checkpoint, in the order they came
+enum InFlight {
+    Change(BrokerMessage, Confirmation),
+    Checkpoint(u64),
+}
+
+/// What the worker waited for
+enum Event {
+    Confirmed(Result<(), String>),
+    Received(Option<Item>),
+}
+
+async fn publisher_worker(
+    config: PublisherSinkConfig,
+    broker: BoxFuture<'static, Result<Arc<dyn Broker>, String>>,
+    mut rx: Receiver<Item>,
+    confirmed: Arc<AtomicU64>,
+) {
+    let broker = match broker.await {
+        Ok(broker) => broker,
+        Err(e) => {
+            error!("Publisher: cannot connect to {}: {}", config.url, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+    let max_in_flight = config.max_in_flight.max(1);
+    let mut backoff = Backoff::new(RETRY_DELAY_MIN, RETRY_DELAY_MAX, 0.2);
+    // Did a change fail to be published, nothing is published after it until it is
+    let mut stalled = false;
+
+    loop {
+        // Every change before the checkpoint has been confirmed
+        while let Some(InFlight::Checkpoint(lsn)) = in_flight.front() {
+            confirmed.store(*lsn, Ordering::Release);
+            in_flight.pop_front();
+        }
+
+        let receive = !stalled && in_flight.len() < max_in_flight;
+        // The changes are confirmed in order, only the first one is waited for
+        let first = match in_flight.front_mut() {
+            Some(InFlight::Change(_, confirmation)) => Some(confirmation),
+            _ => None,
+        };
+        let event = tokio::select! {
+            Some(result) = OptionFuture::from(first) => Event::Confirmed(result),
+            item = rx.recv(), if receive => Event::Received(item),
+        };
+
+        match event {
+            Event::Confirmed(Ok(())) => {
+                in_flight.pop_front();
+                backoff.reset();
+            }
+            Event::Confirmed(Err(e)) => {
+                // Publish it again along with every change after it, so that the
+                // broker gets them in order (a change never overtakes an older one).
+                let delay = backoff.next_delay();
+                if let Some(InFlight::Change(message, _)) = in_flight.front() {
+                    warn!(
+                        "Publisher: the change at {} to {} is not confirmed: {}, publishing it again (and those after it) in {:?}",
+                        format_lsn(message.lsn),
+                        message.subject,
+                        e,
+                        delay
+                    );
+                }
+                tokio::time::sleep(delay).await;
+                stalled = !republish(broker.as_ref(), &mut in_flight).await;
+            }
+            Event::Received(Some(Item::Change(event))) => {
+                let message = broker_message(&config, &event);
+                let (confirmation, sent) = publish(broker.as_ref(), &message).await;
+                stalled = !sent;
+                in_flight.push_back(InFlight::Change(message, confirmation));
+            }
+            Event::Received(Some(Item::Checkpoint(lsn))) => {
+                in_flight.push_back(InFlight::Checkpoint(lsn))
+            }
+            Event::Received(None) => return,
+        }
+    }
+}
+
+/// Publish the message, a failure to send it being a refused confirmation.
+///
+/// Return false along with it in that case.
+async fn publish(broker: &dyn Broker, message: &BrokerMessage) -> (Confirmation, bool) {
+    match broker.publish(message).await {
+        Ok(confirmation) => (confirmation, true),
+        Err(e) => (Box::pin(future::ready(Err(e))), false),
+    }
+}
+
+/// Publish every change not confirmed yet again, in order, stopping at the
+/// first one which cannot be sent. Return false if one couldn't.
+async fn republish(broker: &dyn Broker, in_flight: &mut VecDeque<InFlight>) -> bool {
+    for item in in_flight.iter_mut() {
+        if let InFlight::Change(message, confirmation) = item {
+            let (sent, ok) = publish(broker, message).await;
+            *confirmation = sent;
+            if !ok {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn broker_message(config: &PublisherSinkConfig, event: &ChangeEvent) -> BrokerMessage {
+    // serde_json::to_vec cannot fail on a Value
+    let payload = serde_json::to_vec(&serializer::serialize(event, config.format, None)).unwrap();
+
+    BrokerMessage {
+        subject: config.subject.replace("{table}", &event.table),
+        key: partition_key(config, event),
+        lsn: event.lsn,
+        payload: Bytes::from(payload),
+    }
+}
+
+/// Get the partition key of the change: the value of the configured column,
+/// or of the primary key of the table (`,` separated).
+fn partition_key(config: &PublisherSinkConfig, event: &ChangeEvent) -> Option<String> {
+    match &config.partition_key {
+        Some(column) => event.column(column).map(|col| key_value(&col.value)),
+        None => {
+            let columns = COLUMNS.read().unwrap();
+            let values: Vec<String> = columns
+                .get(&event.table)?
+                .keys
+                .iter()
+                .filter_map(|key| event.column(key))
+                .map(|col| key_value(&col.value))
+                .collect();
+
+            if values.is_empty() {
+                None
+            } else {
+                Some(values.join(","))
+            }
+        }
+    }
+}
+
+fn key_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.to_owned(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::ws_utils::MessageFormat,
+        cdc::{
+            event::{ChangeKind, Position},
+            progress::Progress,
+        },
+    };
+
+    use std::{sync::Mutex, time::Duration};
+    use tokio::sync::oneshot;
+
+    /// A message published and what confirm it (until answered)
+    type Published = (BrokerMessage, Option<oneshot::Sender<Result<(), String>>>);
+
+    /// Broker whose confirmations are given (or refused) by the test
+    #[derive(Default)]
+    struct MockBroker {
+        /// The messages published (retries included)
+        published: Mutex<Vec<Published>>,
+        /// Number of the next publications to fail right away
+        failures: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl Broker for MockBroker {
+        async fn publish(&self, message: &BrokerMessage) -> Result<Confirmation, String> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(String::from("not connected"));
+            }
+
+            let (tx, rx) = oneshot::channel();
+            self.published
+                .lock()
+                .unwrap()
+                .push((message.clone(), Some(tx)));
+            Ok(Box::pin(async move {
+                rx.await.unwrap_or_else(|_| Err(String::from("dropped")))
+            }))
+        }
+    }
+
+    impl MockBroker {
+        /// Wait for the worker to have published count messages
+        async fn wait_published(&self, count: usize) {
+            for _ in 0..10_000 {
+                if self.published.lock().unwrap().len() >= count {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            panic!("expected {} messages to be published", count);
+        }
+
+        fn lsns(&self) -> Vec<u64> {
+            let published = self.published.lock().unwrap();
+            published.iter().map(|(message, _)| message.lsn).collect()
+        }
+
+        /// Answer to the nth publication, and let the worker handle it
+        async fn confirm(&self, nth: usize, result: Result<(), String>) {
+            let tx = self.published.lock().unwrap()[nth].1.take().unwrap();
+            tx.send(result).unwrap();
+            settle().await;
+        }
+    }
+
+    /// Let the worker process everything it can (the time is paused)
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    fn event(lsn: u64) -> Arc<ChangeEvent> {
+        Arc::new(ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Insert,
+            columns: Vec::new(),
+            old_keys: Vec::new(),
+            lsn,
+            position: Position::new(lsn, 0),
+            commit_ts: None,
+            xid: None,
+        })
+    }
+
+    /// Feed the worker the way the forwarder does, the progress
+    /// being only told once the checkpoint is queued.
+    struct Forwarder {
+        tx: Sender<Item>,
+        progress: Progress,
+        broker: Arc<MockBroker>,
+    }
+
+    impl Forwarder {
+        fn start(max_in_flight: usize) -> Self {
+            let config = PublisherSinkConfig {
+                query: String::from("*:hosts"),
+                format: MessageFormat::Envelope,
+                url: String::from("mock://"),
+                subject: String::from("cdc.{table}"),
+                partition_key: Some(String::from("uuid")),
+                max_in_flight,
+            };
+            let broker = Arc::new(MockBroker::default());
+            let connect: Arc<dyn Broker> = broker.clone();
+            let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+            let confirmed = Arc::new(AtomicU64::new(0));
+            tokio::spawn(publisher_worker(
+                config,
+                Box::pin(future::ready(Ok(connect))),
+                rx,
+                confirmed.clone(),
+            ));
+
+            Self {
+                tx,
+                progress: Progress::new(vec![confirmed]),
+                broker,
+            }
+        }
+
+        async fn change(&self, lsn: u64) {
+            self.tx.send(Item::Change(event(lsn))).await.unwrap();
+        }
+
+        async fn checkpoint(&self, lsn: u64) {
+            self.tx.send(Item::Checkpoint(lsn)).await.unwrap();
+            self.progress.forwarded(lsn);
+            settle().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn processed_waits_for_every_earlier_confirmation() {
+        let forwarder = Forwarder::start(16);
+        for lsn in [10, 20, 30] {
+            forwarder.change(lsn).await;
+            forwarder.checkpoint(lsn + 5).await;
+        }
+        let broker = &forwarder.broker;
+        broker.wait_published(3).await;
+        assert_eq!(forwarder.progress.processed(), 0);
+
+        // Confirmed out of order, the first change is not yet
+        broker.confirm(2, Ok(())).await;
+        broker.confirm(1, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 0);
+
+        broker.confirm(0, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 35);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn checkpoint_after_an_unconfirmed_change() {
+        let forwarder = Forwarder::start(16);
+        forwarder.change(10).await;
+        forwarder.checkpoint(15).await;
+        forwarder.change(20).await;
+        forwarder.checkpoint(25).await;
+        let broker = &forwarder.broker;
+        broker.wait_published(2).await;
+
+        broker.confirm(0, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 15);
+
+        // The next checkpoint doesn't pass the unconfirmed change either
+        forwarder.change(30).await;
+        forwarder.checkpoint(35).await;
+        broker.wait_published(3).await;
+        broker.confirm(2, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 15);
+
+        broker.confirm(1, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 35);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_changes_are_published_again_in_order() {
+        let forwarder = Forwarder::start(16);
+        let broker = &forwarder.broker;
+        // The first publication fails right away, then its confirmation fails
+        *broker.failures.lock().unwrap() = 1;
+        forwarder.change(10).await;
+        forwarder.checkpoint(15).await;
+        forwarder.change(20).await;
+        forwarder.checkpoint(25).await;
+
+        // The second change waits for the first one to be published
+        broker.wait_published(2).await;
+        assert_eq!(broker.lsns(), vec![10, 20]);
+
+        // Both are published again, in the same order
+        broker.confirm(0, Err(String::from("timeout"))).await;
+        broker.wait_published(4).await;
+        assert_eq!(broker.lsns(), vec![10, 20, 10, 20]);
+        assert_eq!(forwarder.progress.processed(), 0);
+
+        broker.confirm(2, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 15);
+        broker.confirm(3, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 25);
+
+        // Nothing else was published
+        forwarder.change(30).await;
+        forwarder.checkpoint(35).await;
+        broker.wait_published(5).await;
+        assert_eq!(broker.lsns(), vec![10, 20, 10, 20, 30]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_in_flight() {
+        let forwarder = Forwarder::start(2);
+        for lsn in [10, 20, 30] {
+            forwarder.change(lsn).await;
+        }
+        forwarder.checkpoint(35).await;
+        let broker = &forwarder.broker;
+        broker.wait_published(2).await;
+        settle().await;
+        assert_eq!(broker.lsns(), vec![10, 20]);
+
+        broker.confirm(0, Ok(())).await;
+        broker.wait_published(3).await;
+        broker.confirm(1, Ok(())).await;
+        broker.confirm(2, Ok(())).await;
+        assert_eq!(forwarder.progress.processed(), 35);
+    }
+}
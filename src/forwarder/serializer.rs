@@ -0,0 +1,385 @@
+use crate::{
+    api::{
+        gate::Frame,
+        ws_utils::{Encoding, MessageFormat},
+    },
+    cdc::{
+        event::{ChangeEvent, ChangeKind, Column},
+        replication::format_lsn,
+    },
+    CONFIG,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use serde_json::{json, Map, Value};
+use std::convert::TryFrom;
+
+/// Version of the envelope, to be bumped on any breaking change of its shape
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Serialize the event into the format asked for by the client.
+///
+/// If select is defined, only those columns are part of the message.
+pub fn serialize(event: &ChangeEvent, format: MessageFormat, select: Option<&[String]>) -> Value {
+    match select {
+        Some(select) => {
+            let projected = ChangeEvent {
+                columns: event
+                    .columns
+                    .iter()
+                    .filter(|c| select.contains(&c.name))
+                    .cloned()
+                    .collect(),
+                ..event.clone()
+            };
+            serialize_event(&projected, format)
+        }
+        None => serialize_event(event, format),
+    }
+}
+
+fn serialize_event(event: &ChangeEvent, format: MessageFormat) -> Value {
+    match format {
+        MessageFormat::Wal2json => wal2json(event),
+        MessageFormat::Envelope => envelope(event),
+    }
+}
+
+/// Serialize the event into the shape of the wal2json change it comes from
+/// (or would have come from with pgoutput).
+fn wal2json(event: &ChangeEvent) -> Value {
+    match CONFIG.legacy_version() {
+        2 => wal2json_v2(event),
+        _ => wal2json_v1(event),
+    }
+}
+
+/// Serialize the event into the versioned envelope, which does not depend
+/// on the output plugin: {v, table, op, lsn, commit_ts, new, old}
+fn envelope(event: &ChangeEvent) -> Value {
+    let new = match event.kind {
+        ChangeKind::Delete => Value::Null,
+        _ => row(&event.columns),
+    };
+    let old = match event.old_keys.is_empty() {
+        true => Value::Null,
+        false => row(&event.old_keys),
+    };
+
+    json!({
+        "v": ENVELOPE_VERSION,
+        "table": event.table,
+        "op": event.kind.as_str(),
+        "lsn": format_lsn(event.lsn),
+        "commit_ts": event.commit_ts.map(|ts| ts.to_rfc3339()),
+        "new": new,
+        "old": old,
+    })
+}
+
+/// Build an object {name: value} out of the columns
+fn row(columns: &[Column]) -> Value {
+    Value::Object(
+        columns
+            .iter()
+            .map(|c| (c.name.clone(), c.value.clone()))
+            .collect(),
+    )
+}
+
+/// Tag the message with the id of the subscription it matched (if any)
+pub fn tagged(mut message: Value, sub: Option<&str>) -> Value {
+    if let (Some(obj), Some(sub)) = (message.as_object_mut(), sub) {
+        obj.insert("sub".to_owned(), Value::from(sub));
+    }
+    message
+}
+
+/// Encode the message into what will be sent over the websocket,
+/// Text for JSON and Binary for the others.
+pub fn encode(message: &Value, encoding: Encoding) -> Frame {
+    match encoding {
+        Encoding::Json => Frame::text(message.to_string()),
+        // Serializing a Value into a Vec cannot fail
+        Encoding::Msgpack => Frame::Binary(rmp_serde::to_vec_named(message).unwrap().into()),
+        Encoding::Cbor => {
+            let mut buf = Vec::new();
+            ciborium::into_writer(message, &mut buf).unwrap();
+            Frame::Binary(buf.into())
+        }
+    }
+}
+
+/// Same as tagged, but on the encoded message: the field is added without
+/// decoding and encoding it again. Unchanged if the message is not a map.
+pub fn tag_encoded(message: &Frame, encoding: Encoding, sub: &str) -> Frame {
+    let spliced = match (message, encoding) {
+        (Frame::Text(json), Encoding::Json) => tag_json(json, sub),
+        (Frame::Binary(data), Encoding::Msgpack) => tag_msgpack(data, sub),
+        (Frame::Binary(data), Encoding::Cbor) => tag_cbor(data, sub),
+        _ => None,
+    };
+    match spliced {
+        Some(data) if encoding == Encoding::Json => Frame::Text(data.into()),
+        Some(data) => Frame::Binary(data.into()),
+        None => message.clone(),
+    }
+}
+
+/// `{...}` becomes `{...,"sub":"id"}`
+fn tag_json(json: &[u8], sub: &str) -> Option<Vec<u8>> {
+    let body = json.strip_prefix(b"{")?.strip_suffix(b"}")?;
+    let mut out = Vec::with_capacity(json.len() + sub.len() + 10);
+    out.push(b'{');
+    out.extend_from_slice(body);
+    if !body.is_empty() {
+        out.push(b',');
+    }
+    out.extend_from_slice(br#""sub":"#);
+    // Serializing a str cannot fail
+    serde_json::to_writer(&mut out, sub).unwrap();
+    out.push(b'}');
+    Some(out)
+}
+
+/// Increment the length of the map and append the field to its entries
+fn tag_msgpack(data: &[u8], sub: &str) -> Option<Vec<u8>> {
+    let (len, header) = match *data.first()? {
+        b @ 0x80..=0x8f => ((b & 0x0f) as u64, 1),
+        0xde => (BigEndian::read_u16(data.get(1..3)?) as u64, 3),
+        0xdf => (BigEndian::read_u32(data.get(1..5)?) as u64, 5),
+        _ => return None,
+    };
+
+    let mut out = Vec::with_capacity(data.len() + sub.len() + 16);
+    match len + 1 {
+        len @ 0..=15 => out.push(0x80 | len as u8),
+        len @ 16..=0xffff => {
+            out.push(0xde);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(0xdf);
+            out.extend_from_slice(&u32::try_from(len).ok()?.to_be_bytes());
+        }
+    }
+    out.extend_from_slice(&data[header..]);
+    rmp_serde::encode::write(&mut out, "sub").ok()?;
+    rmp_serde::encode::write(&mut out, sub).ok()?;
+    Some(out)
+}
+
+/// Increment the length of the map and append the field to its entries
+fn tag_cbor(data: &[u8], sub: &str) -> Option<Vec<u8>> {
+    // Major type 5 (map), the additional info giving the length
+    let (len, header) = match *data.first()? {
+        b @ 0xa0..=0xb7 => ((b - 0xa0) as u64, 1),
+        0xb8 => (*data.get(1)? as u64, 2),
+        0xb9 => (BigEndian::read_u16(data.get(1..3)?) as u64, 3),
+        0xba => (BigEndian::read_u32(data.get(1..5)?) as u64, 5),
+        0xbb => (BigEndian::read_u64(data.get(1..9)?), 9),
+        _ => return None,
+    };
+
+    let mut out = Vec::with_capacity(data.len() + sub.len() + 16);
+    match len + 1 {
+        len @ 0..=23 => out.push(0xa0 + len as u8),
+        len @ 24..=0xff => out.extend_from_slice(&[0xb8, len as u8]),
+        len @ 0x100..=0xffff => {
+            out.push(0xb9);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len @ 0x1_0000..=0xffff_ffff => {
+            out.push(0xba);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        len => {
+            out.push(0xbb);
+            out.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+    out.extend_from_slice(&data[header..]);
+    ciborium::into_writer("sub", &mut out).ok()?;
+    ciborium::into_writer(sub, &mut out).ok()?;
+    Some(out)
+}
+
+/// format-version 1: {kind, schema, table, columnnames, columntypes, columnvalues, oldkeys}
+fn wal2json_v1(event: &ChangeEvent) -> Value {
+    let mut change = Map::new();
+    change.insert("kind".to_owned(), Value::from(event.kind.as_str()));
+    change.insert("schema".to_owned(), Value::from(event.schema.as_str()));
+    change.insert("table".to_owned(), Value::from(event.table.as_str()));
+
+    if event.kind != ChangeKind::Delete {
+        change.insert("columnnames".to_owned(), names(&event.columns));
+        change.insert("columntypes".to_owned(), types(&event.columns));
+        change.insert("columnvalues".to_owned(), values(&event.columns));
+    }
+
+    if !event.old_keys.is_empty() {
+        change.insert(
+            "oldkeys".to_owned(),
+            json!({
+                "keynames": names(&event.old_keys),
+                "keytypes": types(&event.old_keys),
+                "keyvalues": values(&event.old_keys),
+            }),
+        );
+    }
+
+    Value::Object(change)
+}
+
+/// format-version 2: {action, schema, table, columns, identity}
+fn wal2json_v2(event: &ChangeEvent) -> Value {
+    let action = match event.kind {
+        ChangeKind::Insert => "I",
+        ChangeKind::Update => "U",
+        ChangeKind::Delete => "D",
+    };
+
+    let mut change = Map::new();
+    change.insert("action".to_owned(), Value::from(action));
+    change.insert("schema".to_owned(), Value::from(event.schema.as_str()));
+    change.insert("table".to_owned(), Value::from(event.table.as_str()));
+
+    if event.kind != ChangeKind::Delete {
+        change.insert("columns".to_owned(), json!(event.columns));
+    }
+
+    if !event.old_keys.is_empty() {
+        change.insert("identity".to_owned(), json!(event.old_keys));
+    }
+
+    Value::Object(change)
+}
+
+fn names(columns: &[Column]) -> Value {
+    columns
+        .iter()
+        .map(|c| Value::from(c.name.as_str()))
+        .collect()
+}
+
+fn types(columns: &[Column]) -> Value {
+    columns
+        .iter()
+        .map(|c| Value::from(c.type_name.as_str()))
+        .collect()
+}
+
+fn values(columns: &[Column]) -> Value {
+    columns.iter().map(|c| c.value.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cdc::{event::StreamMessage, wal2json::Wal2JsonDecoder, Decoder};
+
+    use bytes::Bytes;
+    use std::io::Cursor;
+
+    fn decode(version: u8, data: &str) -> ChangeEvent {
+        let mut decoder = Wal2JsonDecoder::new(version);
+        let messages = decoder
+            .decode(1, &mut Cursor::new(Bytes::from(data.to_owned())))
+            .unwrap();
+        match messages.into_iter().next() {
+            Some(StreamMessage::Change(event)) => event,
+            other => panic!("expected a change, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wal2json_v1_round_trip() {
+        let change = json!({
+            "kind": "update",
+            "schema": "public",
+            "table": "hosts",
+            "columnnames": ["uuid", "cpus"],
+            "columntypes": ["uuid", "integer"],
+            "columnvalues": ["X", 4],
+            "oldkeys": {"keynames": ["uuid"], "keytypes": ["uuid"], "keyvalues": ["X"]},
+        });
+        let event = decode(1, &json!({ "change": [change] }).to_string());
+
+        assert_eq!(wal2json_v1(&event), change);
+    }
+
+    #[test]
+    fn wal2json_v2_round_trip() {
+        let change = json!({
+            "action": "D",
+            "schema": "public",
+            "table": "hosts",
+            "identity": [{"name": "uuid", "type": "uuid", "value": "X"}],
+        });
+        let event = decode(2, &change.to_string());
+
+        assert_eq!(wal2json_v2(&event), change);
+    }
+
+    #[test]
+    fn envelope_shape() {
+        let event = decode(
+            1,
+            r#"{"timestamp":"2022-01-01 10:00:00+00","change":[{"kind":"insert","schema":"public","table":"hosts","columnnames":["uuid"],"columntypes":["uuid"],"columnvalues":["X"]}]}"#,
+        );
+
+        assert_eq!(
+            envelope(&event),
+            json!({
+                "v": ENVELOPE_VERSION,
+                "table": "hosts",
+                "op": "insert",
+                "lsn": "0/1",
+                "commit_ts": "2022-01-01T10:00:00+00:00",
+                "new": {"uuid": "X"},
+                "old": null,
+            })
+        );
+    }
+
+    fn decoded(frame: &Frame, encoding: Encoding) -> Value {
+        match (frame, encoding) {
+            (Frame::Text(json), Encoding::Json) => serde_json::from_slice(json).unwrap(),
+            (Frame::Binary(data), Encoding::Msgpack) => rmp_serde::from_slice(data).unwrap(),
+            (Frame::Binary(data), Encoding::Cbor) => ciborium::from_reader(&data[..]).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tag_encoded_messages() {
+        // Around the sizes where the header of the maps grows
+        for len in [0, 1, 14, 15, 16, 22, 23, 24, 255, 256, 65535] {
+            let message: Map<String, Value> = (0..len)
+                .map(|i| (format!("k{}", i), Value::from(i)))
+                .collect();
+            let message = Value::Object(message);
+
+            for encoding in [Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+                let frame = encode(&message, encoding);
+                let tagged_frame = tag_encoded(&frame, encoding, "hosts \"1\"");
+                assert_eq!(
+                    decoded(&tagged_frame, encoding),
+                    tagged(message.clone(), Some("hosts \"1\"")),
+                    "{} fields in {:?}",
+                    len,
+                    encoding
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tag_encoded_not_a_map() {
+        for encoding in [Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+            let frame = encode(&json!([1, 2]), encoding);
+            assert_eq!(tag_encoded(&frame, encoding, "sub"), frame);
+        }
+    }
+}
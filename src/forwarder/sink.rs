@@ -0,0 +1,224 @@
+use super::{file::FileSink, webhook::WebhookSink};
+#[cfg(feature = "nats")]
+use super::{nats::NatsBroker, publisher::PublisherSink};
+
+use crate::{
+    api::{
+        query,
+        ws_utils::{SystemMessage, WsWatchFor},
+    },
+    cdc::event::{ChangeEvent, Transaction},
+    utils::config::SinkConfig,
+    CONFIG,
+};
+
+use async_trait::async_trait;
+use sproot::apierrors::ApiError;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// Where the forwarder deliver the changes: the websockets, and the sinks of the config.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Deliver the change. As the sinks are fed one after the other, only the
+    /// durable ones should wait (to apply backpressure instead of losing it).
+    async fn send(&self, event: &Arc<ChangeEvent>);
+
+    /// Every change of the transaction has been sent to the sink
+    async fn commit(&self, _tx: &Transaction) {}
+
+    /// Every change up to the position has been sent to the sink
+    async fn checkpoint(&self, _lsn: u64) {}
+
+    /// Deliver the message about the state of the stream (for the clients)
+    async fn system(&self, _message: &SystemMessage) {}
+
+    /// The tables and columns have been (re)detected
+    async fn reloaded(&self) {}
+
+    /// Position up to which the sink confirmed the changes, if their
+    /// acknowledgment to PostgreSQL must wait for it.
+    fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+        None
+    }
+}
+
+pub type Sinks = Arc<Vec<Box<dyn Sink>>>;
+
+/// Build the sinks: the local ones (websockets or bus, depending on the role)
+/// followed by those of the config.
+///
+/// Must be called from the tokio runtime, the sinks spawn their own task.
+pub fn build_sinks(mut sinks: Vec<Box<dyn Sink>>) -> Sinks {
+    for config in &CONFIG.sinks {
+        sinks.push(match config {
+            SinkConfig::Webhook(config) => Box::new(WebhookSink::new(config.clone())),
+            SinkConfig::File(config) => Box::new(FileSink::new(config.clone())),
+            #[cfg(feature = "nats")]
+            SinkConfig::Nats(config) => Box::new(PublisherSink::new(
+                config.clone(),
+                Box::pin(NatsBroker::connect(config.url.clone())),
+            )),
+        });
+    }
+
+    Arc::new(sinks)
+}
+
+/// Send the system message to every sinks
+pub async fn broadcast(sinks: &Sinks, message: &SystemMessage) {
+    for sink in sinks.iter() {
+        sink.system(message).await;
+    }
+}
+
+/// Tell every sinks that the tables and columns have been (re)detected
+pub async fn reloaded(sinks: &Sinks) {
+    for sink in sinks.iter() {
+        sink.reloaded().await;
+    }
+}
+
+/// Check the queries of the sinks of the config, exit if one is invalid.
+///
+/// Must be called once the tables are known (as the queries are checked against them).
+pub fn check_sinks_config() {
+    for config in &CONFIG.sinks {
+        if let Err(err) = parse_sink_query(config.query()) {
+            error!("Sink: the query `{}` is invalid: {}", config.query(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_sink_query(query: &str) -> Result<Vec<WsWatchFor>, String> {
+    query::parse_ws_queries(std::iter::once(query)).map_err(|err| match err {
+        ApiError::ExplicitError(msg) => msg,
+        _ => String::from("invalid query"),
+    })
+}
+
+/// Changes a sink of the config is interested in, using the same syntax as the
+/// websockets. The query is only parsed once the tables are known (first change).
+pub struct SinkFilter {
+    query: String,
+    watch_for: OnceLock<Vec<WsWatchFor>>,
+}
+
+impl SinkFilter {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: query.to_owned(),
+            watch_for: OnceLock::new(),
+        }
+    }
+
+    pub fn matches(&self, event: &ChangeEvent) -> bool {
+        let watch_for = self
+            .watch_for
+            .get_or_init(|| match parse_sink_query(&self.query) {
+                Ok(watch_for) => watch_for,
+                Err(err) => {
+                    // Already checked at startup, the tables changed since
+                    error!("Sink: the query `{}` is invalid: {}", self.query, err);
+                    Vec::new()
+                }
+            });
+
+        watch_for.iter().any(|sub| {
+            sub.change_table == event.table
+                && has_bit!(sub.change_flag, event.kind.flag())
+                && match &sub.specific {
+                    Some(specific) => specific.match_filter(event),
+                    None => true,
+                }
+        })
+    }
+}
+
+/// What the task of a sink receive from its SinkQueue
+pub enum SinkItem {
+    Change(Arc<ChangeEvent>),
+    /// Every change up to this position has been queued, once those before
+    /// it are processed the task can store it as confirmed.
+    Checkpoint(u64),
+}
+
+/// Bounded queue between the forwarder and the task of a sink.
+///
+/// When it's full (the sink is too slow or down), the forwarder waits for some
+/// room and the positions are only acknowledged once confirmed by the task of
+/// the sink. Unless the sink is lossy, in which case the changes are dropped.
+pub struct SinkQueue {
+    /// Name of the sink, for the logs
+    name: String,
+    tx: Sender<SinkItem>,
+    lossy: bool,
+    /// Is the queue currently full (to only log it once)
+    full: AtomicBool,
+    /// Position up to which the task processed the changes
+    confirmed: Arc<AtomicU64>,
+}
+
+impl SinkQueue {
+    pub fn new(name: String, size: usize, lossy: bool) -> (Self, Receiver<SinkItem>) {
+        let (tx, rx) = mpsc::channel(size.max(1));
+        let queue = Self {
+            name,
+            tx,
+            lossy,
+            full: AtomicBool::new(false),
+            confirmed: Arc::new(AtomicU64::new(0)),
+        };
+
+        (queue, rx)
+    }
+
+    pub async fn push(&self, event: &Arc<ChangeEvent>) {
+        let item = SinkItem::Change(Arc::clone(event));
+        if !self.lossy {
+            if self.tx.send(item).await.is_err() {
+                error!(
+                    "Sink: {} has stopped, the changes won't be acknowledged anymore",
+                    self.name
+                );
+            }
+            return;
+        }
+
+        match self.tx.try_send(item) {
+            Ok(_) => self.full.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.full.swap(true, Ordering::Relaxed) {
+                    warn!("Sink: {} is too slow, its queue is full", self.name);
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("Sink: {} has stopped, dropping the change", self.name)
+            }
+        }
+    }
+
+    /// Every change up to the position has been pushed
+    pub async fn checkpoint(&self, lsn: u64) {
+        if !self.lossy && self.tx.send(SinkItem::Checkpoint(lsn)).await.is_err() {
+            error!(
+                "Sink: {} has stopped, the changes won't be acknowledged anymore",
+                self.name
+            );
+        }
+    }
+
+    /// Where the task store the position up to which it processed the changes
+    pub fn processed(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.confirmed)
+    }
+
+    /// Position to wait for before acknowledging the changes, None if lossy
+    pub fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+        (!self.lossy).then(|| self.processed())
+    }
+}
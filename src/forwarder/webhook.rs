@@ -0,0 +1,313 @@
+use super::{
+    serializer,
+    sink::{Sink, SinkFilter, SinkItem, SinkQueue},
+};
+
+use crate::{
+    cdc::{event::ChangeEvent, replication::format_lsn},
+    utils::{backoff::Backoff, config::WebhookSinkConfig},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use sha2::Sha256;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Header carrying the HMAC-SHA256 of the body (`sha256=<hex>`), if a secret is configured
+pub const SIGNATURE_HEADER: &str = "X-Pgcdc-Signature";
+/// Header carrying the LSN of the change
+pub const LSN_HEADER: &str = "X-Pgcdc-Lsn";
+
+/// Delays (in ms) between the retries of a delivery
+const RETRY_DELAY_MIN: u64 = 500;
+const RETRY_DELAY_MAX: u64 = 30000;
+
+/// POST each change matching the query to an url, one after the other,
+/// retrying (with an exponential backoff) when the delivery fails.
+///
+/// Unless lossy, a position is only acknowledged once every change before it
+/// has been delivered (or refused), the deliveries being retried until then.
+pub struct WebhookSink {
+    filter: SinkFilter,
+    queue: SinkQueue,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookSinkConfig) -> Self {
+        let (queue, rx) = SinkQueue::new(
+            format!("webhook {}", config.url),
+            config.queue_size,
+            config.lossy,
+        );
+        let filter = SinkFilter::new(&config.query);
+        tokio::spawn(webhook_worker(config, rx, queue.processed()));
+
+        Self { filter, queue }
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    async fn send(&self, event: &Arc<ChangeEvent>) {
+        if self.filter.matches(event) {
+            self.queue.push(event).await;
+        }
+    }
+
+    async fn checkpoint(&self, lsn: u64) {
+        self.queue.checkpoint(lsn).await;
+    }
+
+    fn confirmed(&self) -> Option<Arc<AtomicU64>> {
+        self.queue.confirmed()
+    }
+}
+
+async fn webhook_worker(
+    config: WebhookSinkConfig,
+    mut rx: Receiver<SinkItem>,
+    confirmed: Arc<AtomicU64>,
+) {
+    let client = match Client::builder()
+        .timeout(Duration::from_millis(config.timeout))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Webhook: cannot build the HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    while let Some(item) = rx.recv().await {
+        match item {
+            SinkItem::Change(event) => {
+                // serde_json::to_vec cannot fail on a Value
+                let body = Bytes::from(
+                    serde_json::to_vec(&serializer::serialize(&event, config.format, None))
+                        .unwrap(),
+                );
+                deliver(&client, &config, event.lsn, body).await;
+            }
+            // Every change before has been delivered
+            SinkItem::Checkpoint(lsn) => confirmed.store(lsn, Ordering::Release),
+        }
+    }
+}
+
+/// Send the body to the webhook, retrying until delivered (up to max_retries times if lossy)
+async fn deliver(client: &Client, config: &WebhookSinkConfig, lsn: u64, body: Bytes) {
+    let signature = config.secret.as_ref().map(|secret| sign(secret, &body));
+    let mut backoff = Backoff::new(RETRY_DELAY_MIN, RETRY_DELAY_MAX, 0.2);
+
+    for attempt in 0.. {
+        if config.lossy && attempt > config.max_retries {
+            break;
+        }
+        if attempt > 0 {
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+
+        let mut request = client
+            .post(&config.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(LSN_HEADER, format_lsn(lsn))
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => return,
+            // The change is refused, retrying won't change anything
+            Ok(resp)
+                if resp.status().is_client_error()
+                    && resp.status() != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                error!(
+                    "Webhook: {} refused the change at {}: {}",
+                    config.url,
+                    format_lsn(lsn),
+                    resp.status()
+                );
+                return;
+            }
+            Ok(resp) => warn!(
+                "Webhook: {} replied {} (attempt {})",
+                config.url,
+                resp.status(),
+                attempt + 1
+            ),
+            Err(e) => warn!(
+                "Webhook: cannot reach {}: {} (attempt {})",
+                config.url,
+                e,
+                attempt + 1
+            ),
+        }
+    }
+
+    error!(
+        "Webhook: giving up on the change at {} for {}",
+        format_lsn(lsn),
+        config.url
+    );
+}
+
+/// Sign the body using HMAC-SHA256
+fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC can take a key of any size
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::ws_utils::MessageFormat,
+        cdc::event::{ChangeKind, Position},
+    };
+
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// Signature, LSN and body of a request
+    type Received = (Option<String>, Option<String>, Bytes);
+
+    /// The test webhook
+    #[derive(Default)]
+    struct Hook {
+        received: Mutex<Vec<Received>>,
+        /// Status of the next replies, 200 once they're all used
+        statuses: Mutex<Vec<u16>>,
+    }
+
+    async fn receive(State(hook): State<Arc<Hook>>, headers: HeaderMap, body: Bytes) -> StatusCode {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        hook.received
+            .lock()
+            .unwrap()
+            .push((header(SIGNATURE_HEADER), header(LSN_HEADER), body));
+
+        let mut statuses = hook.statuses.lock().unwrap();
+        match statuses.is_empty() {
+            true => StatusCode::OK,
+            false => StatusCode::from_u16(statuses.remove(0)).unwrap(),
+        }
+    }
+
+    /// Start a webhook replying with the statuses, return its config
+    async fn webhook(statuses: &[u16], lossy: bool) -> (WebhookSinkConfig, Arc<Hook>) {
+        let hook = Arc::new(Hook::default());
+        *hook.statuses.lock().unwrap() = statuses.to_vec();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(hook.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = WebhookSinkConfig {
+            query: String::from("*:hosts"),
+            format: MessageFormat::Envelope,
+            url: format!("http://{}/hook", addr),
+            secret: Some(String::from("SECRET")),
+            max_retries: 1,
+            timeout: 1000,
+            queue_size: 16,
+            lossy,
+        };
+        (config, hook)
+    }
+
+    fn event() -> Arc<ChangeEvent> {
+        Arc::new(ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Delete,
+            columns: Vec::new(),
+            old_keys: Vec::new(),
+            lsn: 0x16B3748,
+            position: Position::new(0x16B3800, 0),
+            commit_ts: None,
+            xid: Some(735),
+        })
+    }
+
+    /// Push the change and a checkpoint to the worker, return the position it confirmed
+    async fn run_worker(config: WebhookSinkConfig) -> u64 {
+        let (queue, rx) = SinkQueue::new(String::from("webhook"), 16, config.lossy);
+        let confirmed = queue.processed();
+        queue.push(&event()).await;
+        queue.checkpoint(0x16B3900).await;
+        // The worker stops once every item has been processed
+        drop(queue);
+        webhook_worker(config, rx, confirmed.clone()).await;
+        confirmed.load(Ordering::Acquire)
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_delivery() {
+        let (config, hook) = webhook(&[], false).await;
+        assert_eq!(run_worker(config).await, 0x16B3900);
+
+        let received = hook.received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (signature, lsn, body) = &received[0];
+        assert_eq!(signature.as_deref(), Some(sign("SECRET", body).as_str()));
+        assert_eq!(lsn.as_deref(), Some("0/16B3748"));
+        let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(body["table"], "hosts");
+    }
+
+    #[tokio::test]
+    async fn retried_until_delivered() {
+        // More failures than max_retries, the change is still delivered
+        let (config, hook) = webhook(&[503, 500], false).await;
+        assert_eq!(run_worker(config).await, 0x16B3900);
+        assert_eq!(hook.received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn lossy_gives_up() {
+        let (config, hook) = webhook(&[503, 503, 503], true).await;
+        // Lossy sinks don't confirm anything, their changes are not waited for
+        assert_eq!(run_worker(config).await, 0);
+        assert_eq!(hook.received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refused_change() {
+        let (config, hook) = webhook(&[400], false).await;
+        assert_eq!(run_worker(config).await, 0x16B3900);
+        assert_eq!(hook.received.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+use super::{serializer, sink::Sink};
+
+use crate::{
+    api::{
+        gate::{Frame, Gate},
+        subscriptions::Subscriber,
+        ws_utils::{Encoding, MessageFormat, ServerState, SystemMessage},
+    },
+    cdc::event::{ChangeEvent, Position, Transaction},
+    CONFIG,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+};
+
+/// What identify a message sent to the clients (before being tagged):
+/// its format, encoding and selected columns.
+type MessageKey<'a> = (MessageFormat, Encoding, Option<&'a [String]>);
+
+/// The changes of a transaction matching the subscriptions of a client in tx mode
+pub struct Batch {
+    gate: Gate,
+    encoding: Encoding,
+    /// Position of the last change of the batch
+    position: Position,
+    changes: Vec<Value>,
+    /// Has the transaction more changes than tx_max_changes
+    too_big: bool,
+}
+
+/// The batches of a transaction, keyed by the client's id
+pub type Batches = HashMap<usize, Batch>;
+
+/// Send the change to the subscribers whose filter match it, or add it
+/// to the batch of the transaction for those in tx mode.
+pub fn send_message(event: &ChangeEvent, subscribers: &[Arc<Subscriber>], batches: &mut Batches) {
+    // What the clients will receive (without any select) for each format, only built if needed
+    let mut values: HashMap<MessageFormat, Value> = HashMap::new();
+    // The encoded messages, to only serialize them once and not once per client
+    let mut messages: HashMap<MessageKey, Frame> = HashMap::new();
+    // and once tagged with the id of a subscription
+    let mut tagged: HashMap<(MessageKey, &str), Frame> = HashMap::new();
+
+    for subscriber in subscribers {
+        let sub = &subscriber.watch_for;
+        // Check if the subscription asked for a particular filter
+        let to_send = match &sub.specific {
+            Some(specific) => specific.match_filter(event),
+            None => true,
+        };
+
+        // Only tag the messages of the sessions with multiple subscriptions (or control messages)
+        let tag = match subscriber.tagged.load(Ordering::Relaxed) {
+            true => Some(sub.id.as_str()),
+            false => None,
+        };
+
+        if to_send && subscriber.tx {
+            let batch = batches.entry(subscriber.client).or_insert_with(|| Batch {
+                gate: subscriber.gate.clone(),
+                encoding: subscriber.encoding,
+                position: event.position,
+                changes: Vec::new(),
+                too_big: false,
+            });
+            // Nothing will be sent for this transaction anymore
+            if batch.too_big {
+                continue;
+            }
+            let change = serializer::tagged(change(event, subscriber, &mut values), tag);
+            batch.push(change, event.position, CONFIG.tx_max_changes);
+        } else if to_send {
+            // Send the message (tagged with the subscription) to the client
+            let key = (
+                subscriber.format,
+                subscriber.encoding,
+                sub.select.as_deref(),
+            );
+            let message = messages.entry(key).or_insert_with(|| {
+                let value = change(event, subscriber, &mut values);
+                serializer::encode(&value, subscriber.encoding)
+            });
+            let message = match tag {
+                // The tag is added to the encoded message, not serialized again
+                Some(tag) => tagged
+                    .entry((key, tag))
+                    .or_insert_with(|| serializer::tag_encoded(message, subscriber.encoding, tag))
+                    .clone(),
+                None => message.clone(),
+            };
+            if !subscriber.gate.send_change(message, event.position) {
+                error!("Send_message: client disconnected, should be removed soon");
+            }
+        }
+    }
+}
+
+/// Serialize the change for the subscriber (without its tag)
+fn change(
+    event: &ChangeEvent,
+    subscriber: &Subscriber,
+    values: &mut HashMap<MessageFormat, Value>,
+) -> Value {
+    match &subscriber.watch_for.select {
+        Some(select) => serializer::serialize(event, subscriber.format, Some(select)),
+        None => values
+            .entry(subscriber.format)
+            .or_insert_with(|| serializer::serialize(event, subscriber.format, None))
+            .clone(),
+    }
+}
+
+/// Send each batch of the transaction to its client, as a single message.
+///
+/// The clients without any change matching in the transaction have no batch,
+/// nothing is sent to them.
+fn flush(batches: &mut Batches, xid: Option<u32>, commit_ts: Option<DateTime<Utc>>) {
+    for (_, batch) in batches.drain() {
+        batch.send(xid, commit_ts);
+    }
+}
+
+impl Batch {
+    /// Add the change to the batch, unless the transaction has more than max changes
+    /// (if any): the whole transaction won't be sent then, not only a part of it.
+    fn push(&mut self, change: Value, position: Position, max: Option<usize>) {
+        if matches!(max, Some(max) if self.changes.len() >= max) {
+            self.too_big = true;
+            self.changes = Vec::new();
+            return;
+        }
+        self.position = position;
+        self.changes.push(change);
+    }
+
+    /// Send the changes of the batch as a single message, or reject it
+    /// (see Gate::reject) if the transaction is too big.
+    fn send(self, xid: Option<u32>, commit_ts: Option<DateTime<Utc>>) {
+        if self.too_big {
+            self.gate.reject();
+            return;
+        }
+
+        let message = json!({
+            "xid": xid,
+            "commit_ts": commit_ts.map(|ts| ts.to_rfc3339()),
+            "changes": self.changes,
+        });
+        let message = serializer::encode(&message, self.encoding);
+        if !self.gate.send_change(message, self.position) {
+            error!("Flush: client disconnected, should be removed soon");
+        }
+    }
+}
+
+/// Send the changes (from the history) to the subscribers they match.
+///
+/// If the last changes are part of a transaction not committed yet (open), they're
+/// added to the batches of the ServerState instead for the subscribers in tx mode.
+pub fn replay(
+    events: &[Arc<ChangeEvent>],
+    subscribers: &[Arc<Subscriber>],
+    open: bool,
+    pending: &mut Batches,
+) {
+    let transactions: Vec<&[Arc<ChangeEvent>]> = events
+        .chunk_by(|a, b| a.position.commit_lsn == b.position.commit_lsn)
+        .collect();
+
+    for (idx, transaction) in transactions.iter().enumerate() {
+        let mut batches = Batches::new();
+        for event in transaction.iter() {
+            let matching: Vec<Arc<Subscriber>> = subscribers
+                .iter()
+                .filter(|sub| {
+                    sub.watch_for.change_table == event.table
+                        && has_bit!(sub.watch_for.change_flag, event.kind.flag())
+                })
+                .cloned()
+                .collect();
+            send_message(event, &matching, &mut batches);
+        }
+
+        if open && idx == transactions.len() - 1 {
+            pending.extend(batches);
+        } else {
+            flush(&mut batches, transaction[0].xid, transaction[0].commit_ts);
+        }
+    }
+}
+
+/// Deliver the changes to the sessions (websocket and SSE) of the ServerState
+pub struct WebsocketSink {
+    server_state: Arc<ServerState>,
+}
+
+impl WebsocketSink {
+    pub fn new(server_state: Arc<ServerState>) -> Self {
+        Self { server_state }
+    }
+}
+
+#[async_trait]
+impl Sink for WebsocketSink {
+    async fn send(&self, event: &Arc<ChangeEvent>) {
+        // The history is locked while forwarding so that a SSE client resuming either
+        // get the change from the history or from here, but never twice or not at all.
+        let mut history = self.server_state.history.lock().unwrap();
+
+        // Only the subscriptions to the table and change type (and matching value for those
+        // with an equality filter) are candidates, the index is not locked while sending.
+        let subscribers = self.server_state.subscriptions.candidates(event);
+        let mut batches = self.server_state.batches.lock().unwrap();
+        send_message(event, &subscribers, &mut batches);
+
+        history.push(Arc::clone(event));
+    }
+
+    async fn commit(&self, tx: &Transaction) {
+        let mut history = self.server_state.history.lock().unwrap();
+        history.end_transaction();
+
+        let mut batches = self.server_state.batches.lock().unwrap();
+        flush(&mut batches, tx.xid, tx.commit_ts);
+    }
+
+    async fn system(&self, message: &SystemMessage) {
+        // The interrupted transaction will be sent again (as a whole) once resumed
+        if let SystemMessage::StreamDown { .. } = message {
+            let mut history = self.server_state.history.lock().unwrap();
+            history.end_transaction();
+            self.server_state.batches.lock().unwrap().clear();
+        }
+
+        self.server_state.broadcast(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::gate::TOO_BIG_CLOSE_CODE, cdc::event::ChangeKind, utils::config::SlowConsumerPolicy,
+    };
+
+    fn event(index: u32) -> ChangeEvent {
+        ChangeEvent {
+            schema: String::from("public"),
+            table: String::from("hosts"),
+            kind: ChangeKind::Insert,
+            columns: Vec::new(),
+            old_keys: Vec::new(),
+            lsn: 0x16B3748,
+            position: Position::new(0x16B3800, index),
+            commit_ts: None,
+            xid: Some(735),
+        }
+    }
+
+    /// Batch the changes of a transaction of count changes, at most max in a message
+    async fn transaction(policy: SlowConsumerPolicy, count: u32, max: Option<usize>) -> Gate {
+        let gate = Gate::with_limits(0, 16, policy);
+        // A message of a previous transaction, still queued
+        gate.send_change(Frame::text(String::from("previous")), Position::new(1, 0));
+
+        let mut batches = Batches::new();
+        let batch = batches.entry(0).or_insert_with(|| Batch {
+            gate: gate.clone(),
+            encoding: Encoding::Json,
+            position: Position::default(),
+            changes: Vec::new(),
+            too_big: false,
+        });
+        for index in 0..count {
+            batch.push(json!(index), event(index).position, max);
+        }
+        flush(&mut batches, Some(735), None);
+        gate
+    }
+
+    async fn received(gate: &Gate) -> Vec<(Frame, Option<u32>)> {
+        gate.close();
+        let mut frames = Vec::new();
+        while let Some((frame, position)) = gate.recv().await {
+            frames.push((frame, position.map(|position| position.index)));
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn transactions_are_sent_whole() {
+        let gate = transaction(SlowConsumerPolicy::DropOldest, 5, None).await;
+        let frames = received(&gate).await;
+        assert_eq!(frames.len(), 2);
+
+        // The message has the position of the last change
+        let (frame, index) = &frames[1];
+        let message: Value = match frame {
+            Frame::Text(text) => serde_json::from_slice(text).unwrap(),
+            frame => panic!("expected a text frame, got {:?}", frame),
+        };
+        assert_eq!(
+            message,
+            json!({"xid": 735, "commit_ts": null, "changes": [0, 1, 2, 3, 4]})
+        );
+        assert_eq!(*index, Some(4));
+    }
+
+    #[tokio::test]
+    async fn too_big_transactions_are_dropped_whole() {
+        let gate = transaction(SlowConsumerPolicy::DropOldest, 5, Some(2)).await;
+        assert_eq!(gate.dropped(), 1);
+        assert_eq!(
+            received(&gate).await,
+            vec![(Frame::text(String::from("previous")), Some(0))]
+        );
+
+        // Unless it fits
+        let gate = transaction(SlowConsumerPolicy::DropOldest, 2, Some(2)).await;
+        assert_eq!(gate.dropped(), 0);
+        assert_eq!(received(&gate).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn too_big_transactions_disconnect() {
+        let gate = transaction(SlowConsumerPolicy::Disconnect, 5, Some(2)).await;
+        // The messages queued before are still sent
+        assert_eq!(
+            received(&gate).await,
+            vec![
+                (Frame::text(String::from("previous")), Some(0)),
+                (
+                    Frame::Close(TOO_BIG_CLOSE_CODE, "transaction too big"),
+                    None
+                ),
+            ]
+        );
+        assert!(!gate.send(Frame::text(String::from("next"))));
+    }
+}
@@ -1,70 +1,284 @@
-use crate::forwarder::start_forwarder;
+use crate::forwarder::{
+    sink::{self, check_sinks_config, Sinks},
+    start_forwarder,
+};
 #[cfg(feature = "timescale")]
 use crate::TABLES_LOOKUP;
 use crate::{
-    api::ws_utils::ServerState,
+    api::ws_utils::SystemMessage,
     cdc::{
         connection::db_client_start,
-        replication::{replication_slot_create, replication_stream_poll, replication_stream_start},
-        ExtConfig,
+        event::StreamMessage,
+        progress::Progress,
+        replication::{
+            format_lsn, replication_slot_create, replication_slot_get_or_create,
+            replication_stream_poll, replication_stream_start,
+        },
+        CdcError, ExtConfig, TableColumns,
     },
+    utils::backoff::Backoff,
 };
-use crate::{SUPERVISOR, TABLES};
+use crate::{COLUMNS, CONFIG, SUPERVISOR, TABLES};
 
 use bastion::prelude::BastionContext;
 use bastion::spawn;
-use std::sync::Arc;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_postgres::{Client, CopyBothDuplex};
+
+/// State of the replication stream as told to the clients, kept outside of the
+/// children so that a restart doesn't make them miss the stream_resumed message.
+#[derive(Default)]
+struct StreamState {
+    /// Since when the stream is down (if it is)
+    down: Option<DateTime<Utc>>,
+    /// Position up to which the changes have been forwarded
+    last_lsn: u64,
+    /// Schema detected the last time the stream was started (None before the first time)
+    schema: Option<Schema>,
+}
+
+impl StreamState {
+    /// Keep the schema detected when (re)starting the stream, return the
+    /// schema_reloaded message to send if it changed since the last time.
+    fn reload(&mut self, schema: Schema) -> Option<SystemMessage> {
+        let tables = schema.tables.clone();
+        match self.schema.replace(schema) {
+            Some(previous) if Some(&previous) != self.schema.as_ref() => {
+                Some(SystemMessage::SchemaReloaded { tables })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tables and columns of the database, as detected when starting the stream
+#[derive(Debug, PartialEq, Eq)]
+struct Schema {
+    tables: Vec<String>,
+    columns: HashMap<String, TableColumns>,
+}
+
+impl Schema {
+    /// The schema of the TABLES and COLUMNS detected
+    fn detected() -> Self {
+        let mut tables = TABLES.read().unwrap().clone();
+        // Their order is not part of the schema
+        tables.sort_unstable();
+        Self {
+            tables,
+            columns: COLUMNS.read().unwrap().clone(),
+        }
+    }
+}
+
+pub fn start_inner(sinks: Sinks) {
+    let stream = Arc::new(Mutex::new(StreamState::default()));
 
-pub fn start_inner(server_state: Arc<ServerState>) {
     // Start the children in Bastion (allow for restart if fails)
     SUPERVISOR
         .children(|child| {
             child.with_exec(move |_: BastionContext| {
                 trace!("Starting the replication forwarder & listener");
-                let server_state = server_state.clone();
+                let sinks = sinks.clone();
+                let stream = stream.clone();
 
                 async move {
                     // A multi-producer, single-consumer channel queue. Using 128 buffers length.
                     let (tx, rx) = mpsc::channel(128);
+                    // Last WAL position fully forwarded (and confirmed by the durable sinks),
+                    // the only one which can be acknowledged.
+                    let progress = Arc::new(Progress::new(
+                        sinks.iter().filter_map(|sink| sink.confirmed()).collect(),
+                    ));
 
                     // Start listening to the Sender & forward message when receiving one
+                    let forwarder_sinks = sinks.clone();
+                    let forwarder_progress = progress.clone();
                     let handle = spawn! {
-                        start_forwarder(rx, server_state).await;
+                        start_forwarder(rx, forwarder_sinks, forwarder_progress).await;
                     };
 
-                    // Form replication connection & keep the connection open
-                    let client = db_client_start().await;
-
-                    // Detect tables that we'll use to authorize or lookup with timescale
-                    client.detect_tables().await;
-                    trace!("Main: Allowed tables are: {:?}", &TABLES.read().unwrap());
-                    #[cfg(feature = "timescale")]
-                    {
-                        client.detect_lookup().await;
-                        trace!(
-                            "Main: Tables lookup are: {:?}",
-                            &TABLES_LOOKUP.read().unwrap()
-                        );
-                    }
-
-                    let slot_name = uuid_readable_rs::short().replace(' ', "_").to_lowercase();
-                    let lsn = replication_slot_create(&client, &slot_name).await;
-                    let duplex_stream = replication_stream_start(&client, &slot_name, &lsn).await;
-
-                    // call to panic allow us to exit this children and restart a new one
-                    // in case any of the two (replication_stream_poll or handle) exit.
-                    select! {
-                        _ = replication_stream_poll(duplex_stream, tx.clone()) => {
-                            panic!("replication_stream_poll exited, panic to restart")
-                        }
-                        _ = handle => {
-                            panic!("start_forwarder exited, panic to restart")
-                        }
-                    }
+                    // The replication reconnect by itself, but the call to panic allow us
+                    // to exit this children and restart a new one in case the forwarder exit.
+                    let replication =
+                        replicate(tx, progress.clone(), sinks.clone(), stream.clone());
+                    let reason = select! {
+                        _ = replication => "replicate exited, panic to restart",
+                        _ = handle => "start_forwarder exited, panic to restart",
+                    };
+                    // The stream is resumed by the next children
+                    stream_down(&stream, &progress, &sinks).await;
+                    panic!("{}", reason)
                 }
             })
         })
         .expect("Cannot create the Children for Bastion");
 }
+
+/// Stream the changes of the database to the forwarder, reconnecting (with an
+/// exponential backoff) each time the connection is lost or cannot be established.
+///
+/// The clients stay connected and are told when the stream goes down and comes back.
+async fn replicate(
+    tx: Sender<StreamMessage>,
+    progress: Arc<Progress>,
+    sinks: Sinks,
+    stream: Arc<Mutex<StreamState>>,
+) {
+    let mut backoff = Backoff::default();
+
+    loop {
+        match replication_start().await {
+            // The client must be kept alive as long as we stream from it
+            Ok((_client, duplex_stream, resume_lsn)) => {
+                backoff.reset();
+                sink::reloaded(&sinks).await;
+                let (down, started, reloaded) = {
+                    let mut stream = stream.lock().unwrap();
+                    let down = stream.down.take().map(|since| (since, stream.last_lsn));
+                    let started = stream.schema.is_some();
+                    (down, started, stream.reload(Schema::detected()))
+                };
+                if let Some((down_since, last_lsn)) = down {
+                    info!("Replication: the stream is back");
+                    let message = SystemMessage::StreamResumed {
+                        down_since,
+                        downtime_ms: (Utc::now() - down_since).num_milliseconds(),
+                        last_lsn: format_lsn(last_lsn),
+                        resume_lsn,
+                        // A new temporary slot only get the changes made from now on
+                        gap: CONFIG.slot_name.is_none(),
+                    };
+                    sink::broadcast(&sinks, &message).await;
+                }
+                if !started {
+                    // The queries of the sinks can only be checked once the tables are known
+                    check_sinks_config();
+                } else if let Some(message) = reloaded {
+                    info!("Replication: the tables or their columns changed");
+                    sink::broadcast(&sinks, &message).await;
+                }
+
+                replication_stream_poll(duplex_stream, tx.clone(), progress.clone()).await;
+                error!("Replication: the stream exited");
+            }
+            Err(CdcError::Config(msg)) => {
+                // Retrying won't change anything
+                error!("Replication: {}", msg);
+                std::process::exit(1);
+            }
+            Err(CdcError::Postgres(e)) => {
+                error!("Replication: cannot start the stream: {}", e);
+            }
+        }
+
+        stream_down(&stream, &progress, &sinks).await;
+
+        let delay = backoff.next_delay();
+        info!("Replication: reconnecting in {:?}", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Tell the clients that the stream is down, unless they already know it
+async fn stream_down(stream: &Mutex<StreamState>, progress: &Progress, sinks: &Sinks) {
+    let message = {
+        let mut stream = stream.lock().unwrap();
+        if stream.down.is_some() {
+            return;
+        }
+        let since = Utc::now();
+        stream.down = Some(since);
+        // The progress of a restarted children only starts with its first change
+        stream.last_lsn = stream.last_lsn.max(progress.processed());
+        SystemMessage::StreamDown {
+            since,
+            last_lsn: format_lsn(stream.last_lsn),
+        }
+    };
+    sink::broadcast(sinks, &message).await;
+}
+
+/// Connect to the database, detect the tables and start the replication stream.
+///
+/// Return the LSN from which the stream starts along with it.
+async fn replication_start() -> Result<(Client, CopyBothDuplex<Bytes>, String), CdcError> {
+    // Form replication connection & keep the connection open
+    let client = db_client_start().await?;
+
+    // Detect tables that we'll use to authorize or lookup with timescale
+    client.detect_tables().await;
+    trace!("Main: Allowed tables are: {:?}", &TABLES.read().unwrap());
+    client.detect_columns().await;
+    #[cfg(feature = "timescale")]
+    {
+        client.detect_lookup().await;
+        trace!(
+            "Main: Tables lookup are: {:?}",
+            &TABLES_LOOKUP.read().unwrap()
+        );
+    }
+
+    // Either use the permanent slot from the config (and resume from where we
+    // left it) or create a temporary one which will be lost when disconnected.
+    let (slot_name, lsn) = match &CONFIG.slot_name {
+        Some(slot_name) => (
+            slot_name.to_owned(),
+            replication_slot_get_or_create(&client, slot_name).await?,
+        ),
+        None => {
+            let slot_name = uuid_readable_rs::short().replace(' ', "_").to_lowercase();
+            let lsn = replication_slot_create(&client, &slot_name, true).await?;
+            (slot_name, lsn)
+        }
+    };
+    let duplex_stream = replication_stream_start(&client, &slot_name, &lsn).await?;
+
+    Ok((client, duplex_stream, lsn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(tables: &[&str], columns: &[&str]) -> Schema {
+        let table = TableColumns {
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            ..Default::default()
+        };
+        Schema {
+            tables: tables.iter().map(|table| table.to_string()).collect(),
+            columns: tables
+                .iter()
+                .map(|name| (name.to_string(), table.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn schema_reloaded_only_when_changed() {
+        let mut stream = StreamState::default();
+        // Nothing to compare with the first time
+        assert!(stream.reload(schema(&["hosts"], &["uuid"])).is_none());
+        assert!(stream.reload(schema(&["hosts"], &["uuid"])).is_none());
+
+        // A new column
+        let message = stream.reload(schema(&["hosts"], &["uuid", "os"]));
+        assert!(
+            matches!(message, Some(SystemMessage::SchemaReloaded { tables }) if tables == ["hosts"])
+        );
+        assert!(stream.reload(schema(&["hosts"], &["uuid", "os"])).is_none());
+
+        // A new table
+        let message = stream.reload(schema(&["cpustats", "hosts"], &["uuid", "os"]));
+        assert!(matches!(
+            message,
+            Some(SystemMessage::SchemaReloaded { tables }) if tables == ["cpustats", "hosts"]
+        ));
+    }
+}
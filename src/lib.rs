@@ -0,0 +1,106 @@
+//! Quick note about the database table name due to Tailscale:
+//! Static array to hold the tables in the order of creation in the database.
+//! As we use TimescaleDB, each table get partitioned using a pattern like "_hyper_x_y_chunk",
+//! which don't give us the opportunity to detect which table is being updated/inserted.
+//! As the client will connect to the WS using the base table name, this array is used for lookup.
+//! The pattern always follow the same naming convention: "_hyper_(table_creation_order_from_1)_(partition_number)_chunk".
+//! So we use this array to derive the name of the table from the pattern naming chunk.
+
+#[macro_use]
+extern crate log;
+
+macro_rules! has_bit {
+    ($a:expr,$b:expr) => {
+        ($a & $b) != 0
+    };
+}
+
+use crate::cdc::TableColumns;
+use crate::utils::config::Config;
+
+use bastion::supervisor::{ActorRestartStrategy, RestartStrategy, SupervisorRef};
+use bastion::Bastion;
+use clap::{Parser, ValueEnum};
+use clap_verbosity_flag::InfoLevel;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::RwLock;
+use std::time::Duration;
+
+pub mod api;
+pub mod cdc;
+#[cfg(feature = "redis")]
+pub mod edge;
+pub mod forwarder;
+pub mod inner;
+pub mod utils;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+pub struct Args {
+    #[clap(short = 'c', long = "config")]
+    pub config_path: Option<String>,
+
+    /// What this instance does, ingest and edge need the redis feature
+    #[clap(long = "role", value_enum, default_value_t = Role::Standalone)]
+    pub role: Role,
+
+    #[clap(flatten)]
+    pub verbose: clap_verbosity_flag::Verbosity<InfoLevel>,
+}
+
+/// Only one instance can consume the replication slot, the ingest and edge
+/// roles allow to scale the websockets across multiple instances.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Replicate and serve the websockets
+    Standalone,
+    /// Replicate and publish the changes to Redis, for the edge instances
+    Ingest,
+    /// Serve the websockets, fed by the changes the ingest instance published
+    Edge,
+}
+
+/// Our global unique client id counter.
+pub static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "timescale")]
+// Used with TimescaleDB to lookup the table name (disks may be _hyper_1 for example)
+pub static TABLES_LOOKUP: Lazy<RwLock<HashMap<i8, String>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Lazy static of the Config which is loaded from the config file
+pub static CONFIG: Lazy<Config> = Lazy::new(|| match Config::new() {
+    Ok(config) => config,
+    Err(e) => {
+        error!("Cannot build the Config: {}", e);
+        std::process::exit(1);
+    }
+});
+
+// Which table are allowed (hard defined at startup for now)
+// Allow us to avoid accepting websocket which will never be triggered
+pub static TABLES: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// Columns of each allowed table (also detected at startup)
+// Used to check the columns asked for in the select of the websocket
+pub static COLUMNS: Lazy<RwLock<HashMap<String, TableColumns>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Bastion supervisor used to define a custom restart policy for the children
+pub static SUPERVISOR: Lazy<SupervisorRef> = Lazy::new(|| {
+    match Bastion::supervisor(|sp| {
+        sp.with_restart_strategy(RestartStrategy::default().with_actor_restart_strategy(
+            ActorRestartStrategy::LinearBackOff {
+                timeout: Duration::from_secs(3),
+            },
+        ))
+    }) {
+        Ok(sp) => sp,
+        Err(err) => {
+            error!("Cannot create the Bastion supervisor: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+});
@@ -0,0 +1,56 @@
+use crate::CONFIG;
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff (with jitter) between attempts, the default one
+/// (reconnections) is configured using the reconnect_* settings.
+#[derive(Debug)]
+pub struct Backoff {
+    /// Delays in ms
+    min: u64,
+    max: u64,
+    jitter: f64,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(
+            CONFIG.reconnect_delay_min,
+            CONFIG.reconnect_delay_max,
+            CONFIG.reconnect_jitter,
+        )
+    }
+}
+
+impl Backoff {
+    pub fn new(min: u64, max: u64, jitter: f64) -> Self {
+        Self {
+            min,
+            max,
+            jitter,
+            attempt: 0,
+        }
+    }
+
+    /// Get the delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u64.saturating_pow(self.attempt))
+            .min(self.max) as f64;
+        self.attempt = self.attempt.saturating_add(1);
+
+        // Avoid every instances retrying at the exact same time
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let delay = delay * (1.0 + jitter * rand::thread_rng().gen_range(-1.0..=1.0));
+
+        Duration::from_millis(delay as u64)
+    }
+
+    /// Start again from the minimal delay, once connected
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
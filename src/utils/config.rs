@@ -1,9 +1,115 @@
-use crate::Args;
+use crate::{api::ws_utils::MessageFormat, Args};
 
 use clap::Parser;
 use config::ConfigError;
 use serde::Deserialize;
 
+/// Logical decoding output plugins we know how to decode
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputPlugin {
+    Wal2json,
+    Pgoutput,
+}
+
+impl OutputPlugin {
+    /// Name of the plugin as known by PostgreSQL
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputPlugin::Wal2json => "wal2json",
+            OutputPlugin::Pgoutput => "pgoutput",
+        }
+    }
+}
+
+/// What to do when the queue of a client is full (the client is too slow)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest message of the queue to make room for the new one
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Close the websocket of the client
+    Disconnect,
+}
+
+/// Destination of the changes (other than the websockets), declared with [[sinks]]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Webhook(WebhookSinkConfig),
+    File(FileSinkConfig),
+    #[cfg(feature = "nats")]
+    Nats(PublisherSinkConfig),
+}
+
+impl SinkConfig {
+    /// Changes to send to the sink, same syntax as the query param of the websocket
+    pub fn query(&self) -> &str {
+        match self {
+            SinkConfig::Webhook(config) => &config.query,
+            SinkConfig::File(config) => &config.query,
+            #[cfg(feature = "nats")]
+            SinkConfig::Nats(config) => &config.query,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSinkConfig {
+    pub query: String,
+    #[serde(default = "default_sink_format")]
+    pub format: MessageFormat,
+    pub url: String,
+    // Key used to sign the body (HMAC-SHA256), not signed if None
+    pub secret: Option<String>,
+    // Number of retries of a failed delivery before giving up on the change (if lossy)
+    #[serde(default = "default_sink_retries")]
+    pub max_retries: u32,
+    // Timeout (in ms) of each request
+    #[serde(default = "default_sink_timeout")]
+    pub timeout: u64,
+    // Number of changes waiting to be delivered
+    #[serde(default = "default_sink_queue")]
+    pub queue_size: usize,
+    // Drop the changes (instead of waiting) when the queue is full or the delivery failed
+    #[serde(default = "default_sink_lossy")]
+    pub lossy: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FileSinkConfig {
+    pub query: String,
+    #[serde(default = "default_sink_format")]
+    pub format: MessageFormat,
+    // File to append the changes to (one JSON per line), created if needed
+    pub path: String,
+    #[serde(default = "default_sink_queue")]
+    pub queue_size: usize,
+    // Drop the changes (instead of waiting) when the queue is full or cannot be written
+    #[serde(default = "default_sink_lossy")]
+    pub lossy: bool,
+}
+
+#[cfg(feature = "nats")]
+#[derive(Debug, Deserialize, Clone)]
+pub struct PublisherSinkConfig {
+    pub query: String,
+    #[serde(default = "default_sink_format")]
+    pub format: MessageFormat,
+    // Url of the broker (ex: nats://localhost:4222)
+    pub url: String,
+    // Subject (or topic) of the changes, `{table}` is replaced by the name of the table
+    #[serde(default = "default_publisher_subject")]
+    pub subject: String,
+    // Column used as the partition key, the primary key of the table if None
+    pub partition_key: Option<String>,
+    // Number of changes published but not yet confirmed by the broker
+    #[serde(default = "default_publisher_in_flight")]
+    pub max_in_flight: usize,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 
 pub struct Config {
@@ -14,6 +120,26 @@ pub struct Config {
     pub database_password: String,
     #[serde(default = "default_dbtls")]
     pub database_tls: bool,
+    // Name of a permanent replication slot to use, if None a temporary one is created
+    pub slot_name: Option<String>,
+    // Logical decoding output plugin used by the replication slot
+    #[serde(default = "default_plugin")]
+    pub output_plugin: OutputPlugin,
+    // Publication to stream from, needed when using pgoutput
+    pub publication_name: Option<String>,
+    // wal2json format-version, 1 (one message per transaction) or 2 (one message per change)
+    #[serde(default = "default_wal2json_format")]
+    pub wal2json_format_version: u8,
+    // Shape (wal2json format-version) of the messages of the legacy format, see legacy_version()
+    pub legacy_format_version: Option<u8>,
+    // Delays (in ms) between the reconnection attempts to the database, doubling
+    // from min up to max, with a random jitter of +/- reconnect_jitter (fraction of the delay)
+    #[serde(default = "default_reconnect_min")]
+    pub reconnect_delay_min: u64,
+    #[serde(default = "default_reconnect_max")]
+    pub reconnect_delay_max: u64,
+    #[serde(default = "default_reconnect_jitter")]
+    pub reconnect_jitter: f64,
 
     // HTTP API CONFIGS
     #[serde(default = "default_binding")]
@@ -22,6 +148,36 @@ pub struct Config {
     pub https: bool,
     pub key_priv: Option<String>,
     pub key_cert: Option<String>,
+    // Number of messages waiting to be sent to a client before it's considered as slow
+    #[serde(default = "default_queue_size")]
+    pub client_queue_size: usize,
+    #[serde(default = "default_slow_policy")]
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // Number of changes kept in memory for the SSE clients to resume from (0 to disable)
+    #[serde(default = "default_sse_history")]
+    pub sse_history_size: usize,
+    // Max number of changes in a single message (tx=1), unlimited by default
+    // The bigger transactions are dropped (or the client disconnected) as a whole
+    #[serde(default)]
+    pub tx_max_changes: Option<usize>,
+    // Compress the websocket messages (permessage-deflate) when the client supports it (opt-in)
+    #[serde(default)]
+    pub ws_compression: bool,
+    // Level of the compression, from 1 (fastest) to 9 (smallest)
+    #[serde(default = "default_ws_compression_level")]
+    pub ws_compression_level: u32,
+
+    // SINKS CONFIGS
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+
+    // REDIS CONFIGS (ingest and edge roles)
+    #[cfg(feature = "redis")]
+    pub redis_url: Option<String>,
+    // Channel the changes are published to, the schema is kept in `<redis_channel>:schema`
+    #[cfg(feature = "redis")]
+    #[serde(default = "default_redis_channel")]
+    pub redis_channel: String,
 
     #[cfg(feature = "auth")]
     pub cookie_secret: String,
@@ -37,6 +193,16 @@ pub struct Config {
 }
 
 impl Config {
+    /// Shape (wal2json format-version) of the messages of the legacy format: the
+    /// configured one, else the one of wal2json when used, else 1 (with pgoutput).
+    pub fn legacy_version(&self) -> u8 {
+        match (self.legacy_format_version, self.output_plugin) {
+            (Some(version), _) => version,
+            (None, OutputPlugin::Wal2json) => self.wal2json_format_version,
+            (None, OutputPlugin::Pgoutput) => 1,
+        }
+    }
+
     pub fn new() -> Result<Self, ConfigError> {
         let args = Args::parse();
 
@@ -55,6 +221,77 @@ fn default_dbtls() -> bool {
     false
 }
 
+fn default_plugin() -> OutputPlugin {
+    OutputPlugin::Wal2json
+}
+
+fn default_wal2json_format() -> u8 {
+    1
+}
+
+fn default_reconnect_min() -> u64 {
+    500
+}
+
+fn default_reconnect_max() -> u64 {
+    30_000
+}
+
+fn default_reconnect_jitter() -> f64 {
+    0.2
+}
+
+fn default_queue_size() -> usize {
+    256
+}
+
+fn default_slow_policy() -> SlowConsumerPolicy {
+    SlowConsumerPolicy::DropOldest
+}
+
+fn default_sse_history() -> usize {
+    1024
+}
+
+fn default_ws_compression_level() -> u32 {
+    1
+}
+
+fn default_sink_lossy() -> bool {
+    true
+}
+
+fn default_sink_format() -> MessageFormat {
+    MessageFormat::Envelope
+}
+
+fn default_sink_retries() -> u32 {
+    5
+}
+
+fn default_sink_timeout() -> u64 {
+    10000
+}
+
+fn default_sink_queue() -> usize {
+    1024
+}
+
+#[cfg(feature = "redis")]
+fn default_redis_channel() -> String {
+    String::from("speculare:pgcdc")
+}
+
+#[cfg(feature = "nats")]
+fn default_publisher_subject() -> String {
+    String::from("speculare.cdc.{table}")
+}
+
+#[cfg(feature = "nats")]
+fn default_publisher_in_flight() -> usize {
+    256
+}
+
 fn default_https() -> bool {
     false
 }
@@ -1,2 +1,3 @@
+pub mod backoff;
 pub mod config;
 pub mod specific_filter;
@@ -1,51 +1,702 @@
+use crate::cdc::event::{ChangeEvent, Column};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde_json::Value;
+use std::{cmp::Ordering, fmt};
+
 /// List of supported data type
 #[derive(Debug, Clone)]
 pub enum DataType {
     String(String),
     Array(Vec<String>),
+    Null,
+}
+
+/// List of supported operators (PostgREST like)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Neq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    Ilike,
+    Is,
+    In,
+    NotIn,
+}
+
+impl Operator {
+    /// Get the Operator from its name in the query (`not.in` being a single one)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eq" => Some(Operator::Eq),
+            "neq" => Some(Operator::Neq),
+            "gt" => Some(Operator::Gt),
+            "gte" => Some(Operator::Gte),
+            "lt" => Some(Operator::Lt),
+            "lte" => Some(Operator::Lte),
+            "like" => Some(Operator::Like),
+            "ilike" => Some(Operator::Ilike),
+            "is" => Some(Operator::Is),
+            "in" => Some(Operator::In),
+            "not.in" => Some(Operator::NotIn),
+            _ => None,
+        }
+    }
+}
+
+/// Family of the PostgreSQL type of a column, used to compare its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Integer,
+    Float,
+    Numeric,
+    Bool,
+    Uuid,
+    Timestamp,
+    Text,
+    /// The type is unknown (or not handled), use the JSON value's type
+    Unknown,
+}
+
+impl ColumnType {
+    /// Get the ColumnType from the name of the type as sent by wal2json
+    /// (ex: `integer`, `character varying(255)`, `timestamp(3) with time zone`)
+    fn from_name(type_name: &str) -> Self {
+        // Remove the type modifier if any, as in numeric(10,2)
+        let name = match (type_name.find('('), type_name.find(')')) {
+            (Some(start), Some(end)) if start < end => {
+                format!("{}{}", &type_name[..start], &type_name[end + 1..])
+            }
+            _ => type_name.to_owned(),
+        };
+
+        match name.as_str() {
+            "smallint" | "integer" | "bigint" | "oid" | "int2" | "int4" | "int8" => {
+                ColumnType::Integer
+            }
+            "real" | "double precision" | "float4" | "float8" => ColumnType::Float,
+            "numeric" | "decimal" => ColumnType::Numeric,
+            "boolean" | "bool" => ColumnType::Bool,
+            "uuid" => ColumnType::Uuid,
+            "timestamp without time zone"
+            | "timestamp with time zone"
+            | "timestamp"
+            | "timestamptz"
+            | "date" => ColumnType::Timestamp,
+            "text" | "character varying" | "character" | "varchar" | "char" | "name" => {
+                ColumnType::Text
+            }
+            _ => ColumnType::Unknown,
+        }
+    }
 }
 
 /// Contains the specific filter applied to the Ws
 #[derive(Debug, Clone)]
 pub struct SpecificFilter {
-    pub column: serde_json::Value,
+    pub column: String,
+    pub op: Operator,
     pub value: DataType,
 }
 
 impl SpecificFilter {
-    /// Determine if the filter match the message passed as parameter
-    pub fn match_filter(&self, message: &serde_json::Value) -> bool {
-        // Determine if the column is present in this change
-        let columns = match message["columnnames"].as_array() {
-            Some(val) => val,
-            None => {
-                error!("Specific: the message does not contains `columnnames`");
-                return false;
+    /// Determine if the filter match the event passed as parameter
+    pub fn match_filter(&self, event: &ChangeEvent) -> bool {
+        // Check if the cloumns we asked for exist in this data change
+        let targeted = match event.column(&self.column) {
+            Some(col) => col,
+            None => return false,
+        };
+        let targeted_value = &targeted.value;
+        // Basically it just match, filter and sort around the criteria of the column value.
+        match (self.op, &self.value) {
+            (Operator::Is, DataType::Null) => targeted_value.is_null(),
+            // Like in SQL, nothing compare to NULL except IS NULL
+            _ if targeted_value.is_null() => false,
+            (Operator::In, DataType::Array(val)) => val
+                .iter()
+                .any(|x| compare(targeted, x) == Some(Ordering::Equal)),
+            (Operator::NotIn, DataType::Array(val)) => val
+                .iter()
+                .all(|x| matches!(compare(targeted, x), Some(o) if o != Ordering::Equal)),
+            (Operator::Like, DataType::String(val)) => match targeted_value.as_str() {
+                Some(t) => like(t, val),
+                None => false,
+            },
+            (Operator::Ilike, DataType::String(val)) => match targeted_value.as_str() {
+                Some(t) => like(&t.to_lowercase(), &val.to_lowercase()),
+                None => false,
+            },
+            (op, DataType::String(val)) => match compare(targeted, val) {
+                Some(ord) => match op {
+                    Operator::Eq => ord == Ordering::Equal,
+                    Operator::Neq => ord != Ordering::Equal,
+                    Operator::Gt => ord == Ordering::Greater,
+                    Operator::Gte => ord != Ordering::Less,
+                    Operator::Lt => ord == Ordering::Less,
+                    Operator::Lte => ord != Ordering::Greater,
+                    _ => false,
+                },
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Boolean expression combining multiple SpecificFilter
+#[derive(Debug, Clone)]
+pub enum FilterExpr {
+    Filter(SpecificFilter),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    /// Determine if the expression match the event passed as parameter
+    pub fn match_filter(&self, event: &ChangeEvent) -> bool {
+        match self {
+            FilterExpr::Filter(filter) => filter.match_filter(event),
+            FilterExpr::And(exprs) => exprs.iter().all(|e| e.match_filter(event)),
+            FilterExpr::Or(exprs) => exprs.iter().any(|e| e.match_filter(event)),
+        }
+    }
+
+    /// Get an equality filter every event matching the expression must match:
+    /// the filter itself or one of the filters of an and().
+    pub fn eq_filter(&self) -> Option<&SpecificFilter> {
+        match self {
+            FilterExpr::Filter(filter) if filter.op == Operator::Eq => Some(filter),
+            FilterExpr::And(exprs) => exprs.iter().find_map(|e| e.eq_filter()),
+            _ => None,
+        }
+    }
+
+    /// Get the name of the columns the expression depends on
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            FilterExpr::Filter(filter) => vec![filter.column.as_str()],
+            FilterExpr::And(exprs) | FilterExpr::Or(exprs) => {
+                exprs.iter().flat_map(|e| e.columns()).collect()
             }
+        }
+    }
+}
+
+/// Normalize the value of a column of type type_name, so that the values equal for
+/// the `eq` operator have the same representation. None if the type does not allow it.
+pub fn index_value(type_name: &str, value: &str) -> Option<String> {
+    match ColumnType::from_name(type_name) {
+        ColumnType::Integer => value.parse::<i64>().ok().map(|v| v.to_string()),
+        ColumnType::Numeric => Decimal::parse(value).map(|v| v.to_string()),
+        ColumnType::Bool => parse_bool(value).map(|v| v.to_string()),
+        ColumnType::Uuid => Some(value.to_lowercase()),
+        ColumnType::Text => Some(value.to_owned()),
+        _ => None,
+    }
+}
+
+/// Normalize the column's value the same way as index_value
+pub fn column_index_value(column: &Column) -> Option<String> {
+    let value = match &column.value {
+        Value::String(s) => s.to_owned(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => return None,
+    };
+
+    index_value(&column.type_name, &value)
+}
+
+/// Compare the column's value with the value of the filter, using the type of the
+/// column: numbers, booleans, uuids and timestamps are compared as such, not as strings.
+///
+/// Return None if both values can't be compared.
+fn compare(targeted: &Column, val: &str) -> Option<Ordering> {
+    // The values can be sent as strings (numeric, bigint, ...) depending on the plugin
+    let as_text = |value: &Value| match value {
+        Value::String(s) => Some(s.to_owned()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+
+    match ColumnType::from_name(&targeted.type_name) {
+        ColumnType::Integer => {
+            let t = as_text(&targeted.value)?;
+            match (t.parse::<i64>(), val.parse::<i64>()) {
+                (Ok(t), Ok(val)) => Some(t.cmp(&val)),
+                // The filter can be a decimal value (id.gt.1.5)
+                _ => Some(Decimal::parse(&t)?.cmp(&Decimal::parse(val)?)),
+            }
+        }
+        ColumnType::Float => as_text(&targeted.value)?
+            .parse::<f64>()
+            .ok()?
+            .partial_cmp(&val.parse::<f64>().ok()?),
+        ColumnType::Numeric => {
+            Some(Decimal::parse(&as_text(&targeted.value)?)?.cmp(&Decimal::parse(val)?))
+        }
+        ColumnType::Bool => {
+            let t = parse_bool(&as_text(&targeted.value)?)?;
+            Some(t.cmp(&parse_bool(val)?))
+        }
+        ColumnType::Uuid => {
+            let t = targeted.value.as_str()?;
+            Some(t.to_lowercase().cmp(&val.to_lowercase()))
+        }
+        ColumnType::Timestamp => {
+            let t = parse_timestamp(targeted.value.as_str()?)?;
+            Some(t.cmp(&parse_timestamp(val)?))
+        }
+        ColumnType::Text => Some(targeted.value.as_str()?.cmp(val)),
+        ColumnType::Unknown => compare_json(&targeted.value, val),
+    }
+}
+
+/// Compare the column's value with the value of the filter, using the JSON value's type
+/// (when the type of the column is not known).
+fn compare_json(targeted: &Value, val: &str) -> Option<Ordering> {
+    match targeted {
+        Value::Number(num) => {
+            let val = val.parse::<f64>().ok()?;
+            num.as_f64()?.partial_cmp(&val)
+        }
+        Value::Bool(b) => Some(b.cmp(&parse_bool(val)?)),
+        Value::String(s) => match (parse_timestamp(s), parse_timestamp(val)) {
+            (Some(t), Some(val)) => Some(t.cmp(&val)),
+            _ => Some(s.as_str().cmp(val)),
+        },
+        _ => None,
+    }
+}
+
+/// A numeric value, compared as a decimal: as a f64 it would lose its precision.
+///
+/// The special values are sorted the way PostgreSQL does: -Infinity first, then the
+/// numbers, Infinity and NaN (equal to itself).
+#[derive(Debug, PartialEq, Eq)]
+enum Decimal {
+    NegInfinity,
+    /// Is it negative, digits of the integer part (without its leading zeros)
+    /// and of the fraction (without its trailing zeros)
+    Finite(bool, String, String),
+    Infinity,
+    NaN,
+}
+
+impl Decimal {
+    /// Parse a decimal number (as 12.50, -1, .5 or 1.2e3) or one of the special values
+    fn parse(val: &str) -> Option<Self> {
+        match val.to_lowercase().as_str() {
+            "nan" => return Some(Decimal::NaN),
+            "infinity" | "+infinity" | "inf" | "+inf" => return Some(Decimal::Infinity),
+            "-infinity" | "-inf" => return Some(Decimal::NegInfinity),
+            _ => {}
+        }
+
+        let (negative, val) = match val.as_bytes().first()? {
+            b'-' => (true, &val[1..]),
+            b'+' => (false, &val[1..]),
+            _ => (false, val),
         };
-        // Check if the cloumns we asked for exist in this data change
-        let value_index = columns.iter().position(|c| c == &self.column);
-        if value_index.is_none() {
-            return false;
+        let (mantissa, exponent) = match val.find(['e', 'E']) {
+            Some(idx) => (&val[..idx], val[idx + 1..].parse::<i32>().ok()?),
+            None => (val, 0),
+        };
+        // Way beyond what a numeric can hold
+        if exponent.abs() > 0x4000 {
+            return None;
         }
-        // Basically it just match, filter and sort around the criteria of the column value.
-        if let Some(col_vals) = message["columnvalues"].as_array() {
-            let targeted_value = &col_vals[value_index.unwrap()];
-            // If the value we asked for is a String or a Number
-            return match &self.value {
-                DataType::String(val) => {
-                    // Check if is_string, and if so, convert it then check
-                    match targeted_value.as_str() {
-                        Some(t) => t == val,
-                        None => false,
-                    }
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if integer.is_empty() && fraction.is_empty()
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+
+        // Move the decimal point by the exponent
+        let digits = format!("{}{}", integer, fraction);
+        let point = integer.len() as i64 + exponent as i64;
+        let (integer, fraction) = if point <= 0 {
+            (String::new(), "0".repeat(-point as usize) + &digits)
+        } else if point as usize >= digits.len() {
+            let zeros = point as usize - digits.len();
+            (digits + &"0".repeat(zeros), String::new())
+        } else {
+            let (integer, fraction) = digits.split_at(point as usize);
+            (integer.to_owned(), fraction.to_owned())
+        };
+
+        let integer = integer.trim_start_matches('0');
+        let fraction = fraction.trim_end_matches('0');
+        // -0 is 0
+        let negative = negative && !(integer.is_empty() && fraction.is_empty());
+        Some(Decimal::Finite(
+            negative,
+            integer.to_owned(),
+            fraction.to_owned(),
+        ))
+    }
+
+    /// Order of the kind of value (the numbers being all of the same kind)
+    fn rank(&self) -> u8 {
+        match self {
+            Decimal::NegInfinity => 0,
+            Decimal::Finite(..) => 1,
+            Decimal::Infinity => 2,
+            Decimal::NaN => 3,
+        }
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (
+                Decimal::Finite(neg, int, frac),
+                Decimal::Finite(other_neg, other_int, other_frac),
+            ) => {
+                // Without leading zeros, the longest integer part is the biggest
+                let magnitude = int
+                    .len()
+                    .cmp(&other_int.len())
+                    .then_with(|| int.cmp(other_int))
+                    .then_with(|| frac.cmp(other_frac));
+                other_neg.cmp(neg).then(match neg {
+                    true => magnitude.reverse(),
+                    false => magnitude,
+                })
+            }
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decimal::NegInfinity => write!(f, "-Infinity"),
+            Decimal::Finite(negative, integer, fraction) => {
+                if *negative {
+                    write!(f, "-")?;
                 }
-                DataType::Array(val) => match targeted_value.as_str() {
-                    Some(t) => val.iter().any(|x| x == t),
-                    None => false,
-                },
-            };
+                match integer.is_empty() {
+                    true => write!(f, "0")?,
+                    false => write!(f, "{}", integer)?,
+                }
+                if !fraction.is_empty() {
+                    write!(f, ".{}", fraction)?;
+                }
+                Ok(())
+            }
+            Decimal::Infinity => write!(f, "Infinity"),
+            Decimal::NaN => write!(f, "NaN"),
+        }
+    }
+}
+
+/// Parse a boolean the way PostgreSQL does (true/false, t/f, yes/no, on/off, 1/0)
+fn parse_bool(val: &str) -> Option<bool> {
+    match val.to_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "on" | "1" => Some(true),
+        "false" | "f" | "no" | "n" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Try to parse a timestamp, either as sent by wal2json (2022-01-01 10:00:00.123+00)
+/// or as RFC3339, a timestamp without time zone being considered as UTC.
+fn parse_timestamp(val: &str) -> Option<DateTime<Utc>> {
+    // Quick check to avoid trying to parse every string as a timestamp
+    let bytes = val.as_bytes();
+    if bytes.len() < 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+
+    if let Ok(ts) = DateTime::parse_from_rfc3339(val) {
+        return Some(ts.with_timezone(&Utc));
+    }
+    if let Ok(ts) = DateTime::parse_from_str(val, "%Y-%m-%d %H:%M:%S%.f%#z") {
+        return Some(ts.with_timezone(&Utc));
+    }
+    for fmt in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(ts) = NaiveDateTime::parse_from_str(val, fmt) {
+            return Some(ts.and_utc());
         }
-        false
+    }
+    NaiveDate::parse_from_str(val, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+/// SQL LIKE matching where `%` (or `*` as in PostgREST) match any
+/// sequence of characters and `_` match any single character.
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    // Position of the last wildcard in the pattern and of the text when we met it
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('%') | Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '_' || *c == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            _ => match backtrack {
+                // Let the wildcard consume one more character
+                Some((bp, bt)) => {
+                    backtrack = Some((bp, bt + 1));
+                    p = bp + 1;
+                    t = bt + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '%' || *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index_value;
+
+    use crate::{
+        api::query::parse_filter_expr,
+        cdc::{
+            event::{ChangeEvent, StreamMessage},
+            wal2json::Wal2JsonDecoder,
+            Decoder,
+        },
+    };
+
+    use bytes::Bytes;
+    use std::io::Cursor;
+
+    /// A wal2json (format-version 1) insert of a row with the values
+    fn fixture(values: &str) -> ChangeEvent {
+        let data = format!(
+            r#"{{"change":[{{"kind":"insert","schema":"public","table":"hosts",
+            "columnnames":["id","ratio","price","active","uuid","created_at","updated_at","notes","name"],
+            "columntypes":["integer","real","numeric(10,2)","boolean","uuid","timestamp without time zone",
+                "timestamp(3) with time zone","text","character varying(255)"],
+            "columnvalues":{}}}]}}"#,
+            values
+        );
+        let mut decoder = Wal2JsonDecoder::new(1);
+        match decoder
+            .decode(1, &mut Cursor::new(Bytes::from(data)))
+            .unwrap()
+            .into_iter()
+            .next()
+        {
+            Some(StreamMessage::Change(event)) => event,
+            other => panic!("expected a change, got {:?}", other),
+        }
+    }
+
+    fn row() -> ChangeEvent {
+        fixture(
+            r#"[42, 0.5, 12.50, true, "7D4E1B44-ED5E-4DBB-B6A3-35DBCC3C6A0E",
+            "2022-01-01 10:00:00", "2022-01-01 10:00:00.123+02", null, "web-1"]"#,
+        )
+    }
+
+    fn matches(event: &ChangeEvent, filter: &str) -> bool {
+        parse_filter_expr(filter).unwrap().match_filter(event)
+    }
+
+    #[test]
+    fn integer() {
+        let row = row();
+        assert!(matches(&row, "id.eq.42"));
+        assert!(matches(&row, "id.gt.41"));
+        assert!(matches(&row, "id.lte.42"));
+        assert!(!matches(&row, "id.lt.42"));
+        // Compared as numbers, not as strings ("42" > "100")
+        assert!(matches(&row, "id.lt.100"));
+        assert!(matches(&row, "id.gt.41.5"));
+        assert!(matches(&row, "id.in.1,42"));
+        assert!(!matches(&row, "id.not.in.1,42"));
+    }
+
+    #[test]
+    fn float_and_numeric() {
+        let row = row();
+        assert!(matches(&row, "ratio.eq.0.5"));
+        assert!(matches(&row, "ratio.gt.0.25"));
+        assert!(matches(&row, "price.eq.12.5"));
+        assert!(matches(&row, "price.lt.100"));
+        assert!(!matches(&row, "price.gt.12.5"));
+    }
+
+    #[test]
+    fn numeric_as_string() {
+        // pgoutput (and wal2json for some types) send the numbers as strings
+        let row = fixture(
+            r#"["42", "0.5", "12345678901234567890.12", "t", "x", null, null, null, null]"#,
+        );
+        assert!(matches(&row, "id.eq.42"));
+        assert!(matches(&row, "ratio.lt.1"));
+        assert!(matches(&row, "price.gt.9999999999999999999"));
+        assert!(matches(&row, "active.eq.true"));
+    }
+
+    #[test]
+    fn numeric_precision() {
+        let row = fixture(r#"[1, 1, "12345678901234567.01", true, null, null, null, null, null]"#);
+        // Equal as f64, but not as numeric
+        assert!(matches(&row, "price.eq.12345678901234567.01"));
+        assert!(matches(&row, "price.eq.012345678901234567.010"));
+        assert!(!matches(&row, "price.eq.12345678901234567.02"));
+        assert!(matches(&row, "price.gt.12345678901234567.009"));
+        assert!(matches(&row, "price.lt.12345678901234567.0100001"));
+        assert!(matches(&row, "price.lt.1.3e16"));
+        assert!(matches(&row, "price.in.1,12345678901234567.01"));
+        assert!(!matches(&row, "price.eq.12345678901234567"));
+
+        let row = fixture(r#"[1, 1, "-0.50", true, null, null, null, null, null]"#);
+        assert!(matches(&row, "price.eq.-.5"));
+        assert!(matches(&row, "price.lt.-0.49"));
+        assert!(matches(&row, "price.gt.-0.51"));
+        assert!(matches(&row, "price.lt.0"));
+        assert!(matches(&row, "price.gt.-Infinity"));
+        assert!(!matches(&row, "price.eq.0.5"));
+
+        // NaN is greater than everything (and equal to itself) in PostgreSQL
+        let row = fixture(r#"[1, 1, "NaN", true, null, null, null, null, null]"#);
+        assert!(matches(&row, "price.eq.NaN"));
+        assert!(matches(&row, "price.gt.Infinity"));
+        assert!(!matches(&row, "price.lt.1"));
+    }
+
+    #[test]
+    fn numeric_index_value() {
+        assert_eq!(
+            index_value("numeric(10,2)", "0012.50").as_deref(),
+            Some("12.5")
+        );
+        assert_eq!(index_value("numeric", "-0.0").as_deref(), Some("0"));
+        assert_eq!(index_value("numeric", "1.5e2").as_deref(), Some("150"));
+        assert_eq!(index_value("numeric", "abc"), None);
+    }
+
+    #[test]
+    fn boolean() {
+        let row = row();
+        assert!(matches(&row, "active.eq.true"));
+        assert!(matches(&row, "active.eq.t"));
+        assert!(matches(&row, "active.neq.false"));
+        assert!(!matches(&row, "active.eq.off"));
+    }
+
+    #[test]
+    fn uuid() {
+        let row = row();
+        // Case insensitive
+        assert!(matches(
+            &row,
+            "uuid.eq.7d4e1b44-ed5e-4dbb-b6a3-35dbcc3c6a0e"
+        ));
+        assert!(!matches(
+            &row,
+            "uuid.eq.00000000-0000-0000-0000-000000000000"
+        ));
+    }
+
+    #[test]
+    fn timestamps() {
+        let row = row();
+        assert!(matches(&row, "created_at.gt.2021-12-31"));
+        assert!(matches(&row, "created_at.eq.2022-01-01T10:00:00Z"));
+        assert!(matches(&row, "created_at.eq.2022-01-01 10:00:00"));
+        // 10:00:00.123+02 is 08:00:00.123 UTC
+        assert!(matches(&row, "updated_at.lt.2022-01-01T09:00:00Z"));
+        assert!(matches(&row, "updated_at.gte.2022-01-01T08:00:00.123Z"));
+        assert!(!matches(&row, "updated_at.gt.2022-01-01T08:00:00.123Z"));
+    }
+
+    #[test]
+    fn null() {
+        let row = row();
+        assert!(matches(&row, "notes.is.null"));
+        assert!(!matches(&row, "id.is.null"));
+        // Like in SQL, nothing compare to NULL
+        assert!(!matches(&row, "notes.eq.x"));
+        assert!(!matches(&row, "notes.neq.x"));
+        assert!(!matches(&row, "notes.in.x,y"));
+    }
+
+    #[test]
+    fn text() {
+        let row = row();
+        assert!(matches(&row, "name.eq.web-1"));
+        assert!(matches(&row, "name.like.web-*"));
+        assert!(matches(&row, "name.ilike.WEB-_"));
+        assert!(!matches(&row, "name.like.db-%"));
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let row = row();
+        // Values which can't be compared with the column's type never match, whatever the operator
+        for filter in [
+            "id.eq.abc",
+            "id.neq.abc",
+            "ratio.gt.abc",
+            "price.lt.abc",
+            "active.eq.maybe",
+            "active.neq.maybe",
+            "created_at.gt.yesterday",
+            "updated_at.neq.2022",
+            "id.not.in.abc,def",
+        ] {
+            assert!(!matches(&row, filter), "{} should not match", filter);
+        }
+
+        // The column's type is used, not the one of the JSON value
+        let row = fixture(r#"["x", 1, 1, 1, 1, 1, 1, 1, 1]"#);
+        assert!(!matches(&row, "id.eq.0"));
+        assert!(matches(&row, "active.eq.true"));
+        assert!(!matches(&row, "uuid.eq.1"));
+        assert!(!matches(&row, "created_at.gt.2021-01-01"));
+    }
+
+    #[test]
+    fn missing_column() {
+        let row = row();
+        assert!(!matches(&row, "missing.eq.1"));
+        assert!(!matches(&row, "missing.is.null"));
+    }
+
+    #[test]
+    fn expressions() {
+        let row = row();
+        assert!(matches(&row, "and(id.eq.42,active.eq.true)"));
+        assert!(!matches(&row, "and(id.eq.42,active.eq.false)"));
+        assert!(matches(
+            &row,
+            "or(id.eq.1,uuid.in.(x,7d4e1b44-ed5e-4dbb-b6a3-35dbcc3c6a0e))"
+        ));
     }
 }